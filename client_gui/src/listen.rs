@@ -1,39 +1,58 @@
-use iced_futures::futures;
+use iced_futures::futures::{self, StreamExt};
 use std::hash::Hash;
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
+use tokio::time::timeout;
 
+use crate::messages::AppMessage;
 use chat_rs::*;
 
+/// How long the server may be quiet before it's pinged, and how long it then has to
+/// answer before the connection is considered lost.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(15);
+
 pub struct Listen {
     unique: Instant,
-    reader: Arc<Mutex<ChatReaderHalf>>,
+    messages: Arc<Mutex<Option<FramedReader>>>,
+    keepalive: bool,
 }
 
 impl Listen {
-    pub fn new(reader: ChatReaderHalf) -> Self {
+    /// Listens to `reader`, pinging the server when it's quiet if `keepalive` is set,
+    /// i.e. if the server is new enough to answer.
+    pub fn new(reader: ChatReaderHalf, keepalive: bool) -> Self {
         Self {
             // TODO: Find a more reliably unique value
             unique: Instant::now(),
-            reader: Arc::new(Mutex::new(reader)),
+            messages: Arc::new(Mutex::new(Some(reader.into_framed()))),
+            keepalive,
         }
     }
 
-    pub fn sub(&self) -> iced::Subscription<Msg> {
-        ListenSubscription::sub(self.reader.clone(), self.unique)
+    pub fn sub(&self) -> iced::Subscription<AppMessage> {
+        ListenSubscription::sub(self.messages.clone(), self.unique, self.keepalive)
     }
 }
 
 pub struct ListenSubscription {
     unique: Instant,
-    reader: Arc<Mutex<ChatReaderHalf>>,
+    messages: Arc<Mutex<Option<FramedReader>>>,
+    keepalive: bool,
 }
 
 impl ListenSubscription {
-    pub fn sub(reader: Arc<Mutex<ChatReaderHalf>>, unique: Instant) -> iced::Subscription<Msg> {
-        iced::Subscription::from_recipe(Self { unique, reader })
+    pub fn sub(
+        messages: Arc<Mutex<Option<FramedReader>>>,
+        unique: Instant,
+        keepalive: bool,
+    ) -> iced::Subscription<AppMessage> {
+        iced::Subscription::from_recipe(Self {
+            unique,
+            messages,
+            keepalive,
+        })
     }
 }
 
@@ -41,7 +60,7 @@ impl<H, I> iced_futures::subscription::Recipe<H, I> for ListenSubscription
 where
     H: std::hash::Hasher,
 {
-    type Output = Msg;
+    type Output = AppMessage;
 
     fn hash(&self, state: &mut H) {
         self.unique.hash(state);
@@ -51,22 +70,56 @@ where
         self: Box<Self>,
         _input: futures::stream::BoxStream<'static, I>,
     ) -> futures::stream::BoxStream<'static, Self::Output> {
-        let mut buffer = [0u8; MSG_LENGTH];
-        Box::pin(futures::stream::unfold(
-            self.reader.clone(),
-            move |reader| async move {
-                let mut guard = reader.lock().await;
-                if let Ok(msg) = guard.receive_msg(&mut buffer).await {
-                    drop(guard);
-                    Some((msg, reader))
-                } else {
-                    // This allow is due to a false positive in this clippy lint
-                    #[allow(clippy::let_unit_value)]
-                    let _: () = iced::futures::future::pending().await;
+        // iced only runs the recipe once for as long as the subscription lives,
+        // so the stream is only ever taken once.
+        let messages = match self.messages.lock().unwrap().take() {
+            Some(messages) => messages,
+            None => return Box::pin(futures::stream::pending()),
+        };
 
-                    None
+        // Stop with a `Disconnected` when the connection is lost (or an `Error` when the
+        // user was kicked, so that the client doesn't reconnect), but never end the
+        // subscription. The state is the reader, and whether the server was pinged.
+        let keepalive = self.keepalive;
+        let events = futures::stream::unfold(Some((messages, false)), move |state| async move {
+            let (mut messages, pinged) = state?;
+            let wait = if pinged { PING_TIMEOUT } else { PING_INTERVAL };
+            let next = if keepalive {
+                timeout(wait, messages.next()).await
+            } else {
+                Ok(messages.next().await)
+            };
+            let (event, pinged) = match next {
+                Ok(Some(Ok(Msg::ConnectionClosed(reason)))) => {
+                    (AppMessage::Disconnected(Some(reason)), false)
+                }
+                Ok(Some(Ok(Msg::ServerShutdown(reason)))) => {
+                    let reason = match reason.as_str() {
+                        "" => "the server is shutting down".to_string(),
+                        reason => format!("the server is shutting down: {}", reason),
+                    };
+                    (AppMessage::Disconnected(Some(reason)), false)
+                }
+                // reconnecting would only get the user kicked again
+                Ok(Some(Ok(Msg::Kicked(reason)))) => {
+                    let reason = format!("Disconnected from server: {}", reason);
+                    (AppMessage::Error(reason), false)
+                }
+                Ok(Some(Ok(msg))) => (AppMessage::ChatMsg(msg), false),
+                Ok(Some(Err(ChatError::Closed)) | None) => (AppMessage::Disconnected(None), false),
+                Ok(Some(Err(e))) => (AppMessage::Disconnected(Some(e.to_string())), false),
+                Err(_) if pinged => {
+                    let reason = "the server stopped responding".to_string();
+                    (AppMessage::Disconnected(Some(reason)), false)
                 }
-            },
-        ))
+                Err(_) => (AppMessage::ServerQuiet, true),
+            };
+            let next = match event {
+                AppMessage::Disconnected(_) | AppMessage::Error(_) => None,
+                _ => Some((messages, pinged)),
+            };
+            Some((event, next))
+        });
+        Box::pin(events.chain(futures::stream::pending()))
     }
 }
@@ -1,15 +1,18 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::bail;
 use iced::{
     alignment::{Horizontal, Vertical},
-    button, executor, scrollable, text_input, Alignment, Application, Button, Column, Command,
-    Container, Element, Length, Row, Scrollable, Settings, Subscription, Text, TextInput,
+    button, executor, scrollable, text_input, Alignment, Application, Button, Checkbox, Column,
+    Command, Container, Element, Length, Row, Scrollable, Settings, Subscription, Text, TextInput,
 };
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
 
 use chat_rs::*;
 
@@ -20,23 +23,67 @@ mod style;
 use listen::*;
 use messages::AppMessage;
 
+const CAPABILITIES: &[Capability] = &[
+    Capability::Encryption,
+    Capability::Fragmentation,
+    Capability::Timestamps,
+];
+/// The limits of the wait between reconnection attempts, which doubles after each
+/// failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The last generation of reconnect attempts handed out. Every lost connection starts a
+/// new one, so that attempts left over from before can be told apart, even after going
+/// back to the login form and connecting again.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
 pub fn main() -> iced::Result {
     ChatClient::run(Settings::default())
 }
 
 enum ChatClient {
-    Error(String),
+    Error {
+        message: String,
+        /// The login form, kept for going back to it.
+        login: LoginState,
+        back_button: button::State,
+    },
     Login(LoginState),
-    Connecting,
+    Connecting(LoginState),
     Ready {
         messages: Vec<Msg>,
-        listener: Listen,
+        connection: Connection,
         writer_channel: mpsc::Sender<Msg>,
         peer_addr: std::net::SocketAddr,
+        /// The user's current nick and room, which are restored after reconnecting.
+        nick: Nick,
+        room: Room,
+        /// Who is in the user's room.
+        roster: Roster,
+        /// The registered nick the user logged in with or registered, and its password.
+        account: Option<(Nick, String)>,
+        /// Whether the user ran `/quit`, in which case they're sent back to the login
+        /// form instead of reconnecting.
+        quitting: bool,
+        /// The generation of the current reconnect attempts. The outcomes of any other
+        /// attempts are dropped.
+        generation: u64,
+        login: Box<LoginState>,
         state: ReadyState,
     },
 }
 
+enum Connection {
+    Connected(Listen),
+    /// The connection was lost, and the next attempt to reconnect is under way.
+    Reconnecting {
+        status: String,
+        /// How long the attempt after the next one waits.
+        backoff: Duration,
+    },
+}
+
 #[derive(Debug, Default)]
 struct LoginState {
     text_addr: text_input::State,
@@ -45,15 +92,23 @@ struct LoginState {
     text_nick: text_input::State,
     text_nick_val: String,
 
+    text_pass: text_input::State,
+    text_pass_val: String,
+
+    /// Whether the user allows connecting to servers that don't encrypt.
+    allow_unencrypted: bool,
+
     login_button: button::State,
 }
 
 #[derive(Debug, Default)]
 struct ReadyState {
     scroll: scrollable::State,
+    roster_scroll: scrollable::State,
     input: text_input::State,
     input_value: String,
     send: button::State,
+    back_button: button::State,
 }
 
 impl Application for ChatClient {
@@ -77,47 +132,44 @@ impl Application for ChatClient {
 
     fn update(&mut self, message: Self::Message) -> Command<Self::Message> {
         if let AppMessage::Error(e) = &message {
-            *self = ChatClient::Error(e.to_string())
+            *self = ChatClient::Error {
+                message: e.to_string(),
+                login: self.take_login(),
+                back_button: button::State::default(),
+            };
+        }
+        if let AppMessage::BackToLogin = message {
+            *self = ChatClient::Login(self.take_login());
+            return Command::none();
         }
         match self {
-            ChatClient::Error(_) => {}
+            ChatClient::Error { .. } => {}
             ChatClient::Login(LoginState {
                 text_addr_val,
                 text_nick_val,
+                text_pass_val,
+                allow_unencrypted,
                 ..
             }) => {
                 use AppMessage::*;
                 match message {
                     AddressChanged(s) => *text_addr_val = s,
                     NickChanged(s) => *text_nick_val = s,
+                    PasswordChanged(s) => *text_pass_val = s,
+                    AllowUnencryptedToggled(allow) => *allow_unencrypted = allow,
                     ButtonPressed => {
-                        let address = text_addr_val.clone();
+                        let address = with_default_port(text_addr_val);
                         let nick = text_nick_val.clone();
+                        let password = text_pass_val.clone();
+                        let allow_unencrypted = *allow_unencrypted;
 
-                        *self = ChatClient::Connecting;
+                        *self = ChatClient::Connecting(self.take_login());
                         return Command::perform(
                             async move {
-                                let stream =
-                                    TcpStream::connect(format!("{}:7878", address)).await?;
-                                let mut stream = ChatStream::new(stream);
-
-                                let mut buffer = [0u8; MSG_LENGTH];
-
-                                stream.send_msg(&Msg::NickChange(nick)).await?;
-
-                                match stream.receive_msg(&mut buffer).await {
-                                    Ok(Msg::ConnectionAccepted) => println!("Connected."),
-                                    Ok(Msg::ConnectionEncrypted) => {
-                                        println!("Connected. Encrypting...");
-                                        stream.encrypt().await?;
-                                    }
-                                    Ok(msg) => bail!("Server refused connection: {}", msg.string()),
-                                    Err(e) => {
-                                        bail!("Error connecting to server: {}", e.to_string())
-                                    }
-                                }
-
-                                Ok(Arc::new(Mutex::new(Some(stream))))
+                                let nick = Nick::new(nick)?;
+                                let (stream, pinned) =
+                                    connect(&address, &nick, &password, allow_unencrypted).await?;
+                                Ok((Arc::new(Mutex::new(Some(stream))), nick, pinned))
                             },
                             AppMessage::or_error(Connected),
                         );
@@ -126,57 +178,146 @@ impl Application for ChatClient {
                 }
             }
 
-            ChatClient::Connecting => {
-                if let AppMessage::Connected(stream) = message {
+            ChatClient::Connecting(_) => {
+                if let AppMessage::Connected((stream, nick, pinned)) = message {
                     let stream = stream.lock().unwrap().take().unwrap();
                     let peer_addr = stream.peer_addr().unwrap();
-
-                    let (reader, mut writer) = stream.into_split();
-                    let listener = Listen::new(reader);
-
-                    let (tx, mut rx) = mpsc::channel::<Msg>(32);
+                    let (listener, writer_channel) = start_session(stream);
+                    let login = self.take_login();
+                    let password = &login.text_pass_val;
 
                     *self = ChatClient::Ready {
-                        messages: vec![],
-                        listener,
-                        writer_channel: tx,
+                        messages: pinned.map(Msg::ServerReply).into_iter().collect(),
+                        connection: Connection::Connected(listener),
+                        writer_channel,
                         peer_addr,
+                        nick: nick.clone(),
+                        room: Room::lobby(),
+                        roster: Roster::default(),
+                        account: (!password.is_empty()).then(|| (nick, password.clone())),
+                        quitting: false,
+                        generation: 0,
+                        login: Box::new(login),
                         state: ReadyState::default(),
                     };
-
-                    tokio::spawn(async move {
-                        while let Some(msg) = rx.recv().await {
-                            writer.send_msg(&msg).await.unwrap();
-                        }
-                    });
                 }
             }
 
             ChatClient::Ready {
                 messages,
+                connection,
                 writer_channel,
+                nick,
+                room,
+                roster,
+                account,
+                quitting,
+                generation,
+                login,
                 state,
                 ..
             } => match message {
+                AppMessage::ChatMsg(Msg::Ping) => return send(writer_channel, Msg::Pong),
+                AppMessage::ChatMsg(Msg::Pong) => {}
+                AppMessage::ServerQuiet => return send(writer_channel, Msg::Ping),
                 AppMessage::ChatMsg(msg) => {
+                    match &msg {
+                        Msg::NickedNickChange(old, new) if old == nick => *nick = new.clone(),
+                        Msg::NickedJoin(joined, new) if joined == nick => *room = new.clone(),
+                        _ => {}
+                    }
+                    roster.update(&msg);
+                    if let Msg::Roster(_, _) = msg {
+                        return Command::none();
+                    }
+                    // a stamped message may be received twice, e.g. as history after
+                    // rejoining a room or reconnecting
+                    if let Some(stamp) = msg.stamp() {
+                        if messages.iter().any(|m| m.stamp() == Some(stamp)) {
+                            return Command::none();
+                        }
+                    }
                     messages.push(msg);
                     if !state.scroll.is_scroller_grabbed() {
                         state.scroll.snap_to(1.0);
                     }
                 }
 
+                AppMessage::Disconnected(reason) => {
+                    if let Connection::Reconnecting { .. } = connection {
+                        return Command::none();
+                    }
+                    if *quitting {
+                        *self = ChatClient::Login(self.take_login());
+                        return Command::none();
+                    }
+                    roster.clear();
+                    let status = match reason {
+                        None => "Disconnected from server.".to_string(),
+                        Some(reason) => format!("Disconnected from server: {}", reason),
+                    };
+                    *connection = Connection::Reconnecting {
+                        status: format!("{} Reconnecting...", status),
+                        backoff: MIN_BACKOFF * 2,
+                    };
+                    *generation = GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+                    return reconnect(login, nick, account, MIN_BACKOFF, *generation);
+                }
+                AppMessage::ReconnectFailed(attempt, _)
+                | AppMessage::ReconnectRefused(attempt, _)
+                | AppMessage::Reconnected(attempt, _, _)
+                    if attempt != *generation =>
+                {
+                    // left over from an earlier connection
+                }
+                AppMessage::ReconnectFailed(_, e) => {
+                    if let Connection::Reconnecting { status, backoff } = connection {
+                        *status = format!(
+                            "Reconnecting failed: {}. Trying again in {}s...",
+                            e,
+                            backoff.as_secs()
+                        );
+                        let wait = *backoff;
+                        *backoff = (wait * 2).min(MAX_BACKOFF);
+                        return reconnect(login, nick, account, wait, *generation);
+                    }
+                }
+                AppMessage::ReconnectRefused(_, e) => return self.update(AppMessage::Error(e)),
+                AppMessage::Reconnected(_, stream, pinned) => {
+                    messages.extend(pinned.map(Msg::ServerReply));
+                    let stream = stream.lock().unwrap().take().unwrap();
+                    let (listener, channel) = start_session(stream);
+                    *connection = Connection::Connected(listener);
+                    *writer_channel = channel;
+                    if *room != Room::lobby() {
+                        return send(writer_channel, Msg::JoinRoom(room.clone()));
+                    }
+                }
+
                 AppMessage::InputChanged(s) => state.input_value = s,
                 AppMessage::Send => {
-                    let msg = Msg::UserMsg(state.input_value.drain(..).collect());
-                    let channel = writer_channel.clone();
-                    return Command::perform(
-                        async move {
-                            channel.send(msg).await?;
-
-                            Ok(())
-                        },
-                        AppMessage::or_error(AppMessage::Sent),
-                    );
+                    if let Connection::Reconnecting { .. } = connection {
+                        // keep the input, so that it can be sent once reconnected
+                        return Command::none();
+                    }
+                    let msg = Msg::from_input(&state.input_value);
+                    state.input_value.clear();
+                    match &msg {
+                        Msg::PrivateMsg(_, _) => {
+                            messages.push(msg.clone());
+                            state.scroll.snap_to(1.0);
+                        }
+                        Msg::Command(command) if command.trim().eq_ignore_ascii_case("quit") => {
+                            *quitting = true;
+                        }
+                        // assume the registration works; if it doesn't, the nick isn't
+                        // registered and the password is ignored when reconnecting
+                        Msg::Register(password) if account_password(account, nick).is_empty() => {
+                            *account = Some((nick.clone(), password.clone()));
+                        }
+                        _ => {}
+                    }
+                    return send(writer_channel, msg);
                 }
 
                 _ => {}
@@ -186,16 +327,20 @@ impl Application for ChatClient {
         Command::none()
     }
 
-    fn view(&mut self) -> Element<Self::Message> {
+    fn view(&mut self) -> Element<'_, Self::Message> {
         match self {
-            ChatClient::Error(s) => {
+            ChatClient::Error {
+                message,
+                back_button,
+                ..
+            } => {
                 let title = Text::new("An error has occured:")
                     .width(Length::Fill)
                     .size(100)
                     .color([0.5, 0.5, 0.5])
                     .horizontal_alignment(Horizontal::Center);
 
-                let error_text = Text::new(s.to_string())
+                let error_text = Text::new(message.to_string())
                     .width(Length::Fill)
                     .size(50)
                     .color([1.0, 0.0, 0.0])
@@ -207,7 +352,8 @@ impl Application for ChatClient {
                     .padding(10)
                     .spacing(10)
                     .push(title)
-                    .push(error_text);
+                    .push(error_text)
+                    .push(back_to_login(back_button, 30));
 
                 Container::new(col)
                     .width(Length::Fill)
@@ -222,6 +368,9 @@ impl Application for ChatClient {
                 text_addr_val,
                 text_nick,
                 text_nick_val,
+                text_pass,
+                text_pass_val,
+                allow_unencrypted,
                 login_button,
             }) => {
                 let title = Text::new("Login")
@@ -248,6 +397,24 @@ impl Application for ChatClient {
                 .padding(15)
                 .size(30);
 
+                let pass_input = TextInput::new(
+                    text_pass,
+                    "Enter your password, if your nickname is registered",
+                    text_pass_val,
+                    AppMessage::PasswordChanged,
+                )
+                .password()
+                .padding(15)
+                .size(30)
+                .on_submit(AppMessage::ButtonPressed);
+
+                let unencrypted = Checkbox::new(
+                    *allow_unencrypted,
+                    "Allow servers that don't encrypt the connection",
+                    AppMessage::AllowUnencryptedToggled,
+                )
+                .size(20);
+
                 let button = Button::new(login_button, Text::new("Connect").size(30))
                     .on_press(AppMessage::ButtonPressed)
                     .padding(15)
@@ -260,6 +427,8 @@ impl Application for ChatClient {
                     .push(title)
                     .push(addr_input)
                     .push(nick_input)
+                    .push(pass_input)
+                    .push(unencrypted)
                     .push(button)
                     .align_items(Alignment::Center);
 
@@ -271,7 +440,7 @@ impl Application for ChatClient {
                     .into()
             }
 
-            ChatClient::Connecting => {
+            ChatClient::Connecting(_) => {
                 let title = Text::new("Connecting...")
                     .width(Length::Fill)
                     .size(100)
@@ -288,12 +457,16 @@ impl Application for ChatClient {
 
             ChatClient::Ready {
                 messages,
+                connection,
+                roster,
                 state:
                     ReadyState {
                         scroll,
+                        roster_scroll,
                         input,
                         input_value,
                         send,
+                        back_button,
                     },
                 ..
             } => {
@@ -329,13 +502,36 @@ impl Application for ChatClient {
                     .push(msg_input)
                     .push(send_button);
 
-                let col = Column::new()
-                    .align_items(Alignment::Center)
+                let chat = Row::new()
                     .width(Length::Fill)
                     .height(Length::Fill)
                     .spacing(10)
                     .push(messages_scroll)
-                    .push(row);
+                    .push(roster_sidebar(roster, roster_scroll));
+
+                let mut col = Column::new()
+                    .align_items(Alignment::Center)
+                    .width(Length::Fill)
+                    .height(Length::Fill)
+                    .spacing(10)
+                    .push(chat);
+
+                if let Connection::Reconnecting { status, .. } = connection {
+                    let status_text = Text::new(status.as_str())
+                        .size(20)
+                        .color([0.7, 0.1, 0.1])
+                        .width(Length::Fill);
+
+                    col = col.push(
+                        Row::new()
+                            .align_items(Alignment::Center)
+                            .width(Length::Fill)
+                            .spacing(10)
+                            .push(status_text)
+                            .push(back_to_login(back_button, 20)),
+                    );
+                }
+                col = col.push(row);
 
                 Container::new(col)
                     .width(Length::Fill)
@@ -350,9 +546,191 @@ impl Application for ChatClient {
 
     fn subscription(&self) -> Subscription<Self::Message> {
         match self {
-            ChatClient::Ready { listener, .. } => listener.sub().map(AppMessage::ChatMsg),
+            ChatClient::Ready {
+                connection: Connection::Connected(listener),
+                ..
+            } => listener.sub(),
 
             _ => Subscription::none(),
         }
     }
 }
+
+impl ChatClient {
+    /// Takes the login form out of the current state, e.g. to go back to it.
+    fn take_login(&mut self) -> LoginState {
+        match self {
+            ChatClient::Error { login, .. }
+            | ChatClient::Login(login)
+            | ChatClient::Connecting(login) => std::mem::take(login),
+            ChatClient::Ready { login, .. } => std::mem::take(login),
+        }
+    }
+}
+
+/// Connects to the server, says hello and logs in as `nick`, encrypting the stream if
+/// the server asks for it. An empty password joins as a guest. Servers that don't
+/// encrypt are refused unless `allow_unencrypted` is set, and always once their
+/// identity is pinned. Returns the stream, along with a notice for the user if the
+/// server's identity was pinned just now.
+async fn connect(
+    address: &str,
+    nick: &Nick,
+    password: &str,
+    allow_unencrypted: bool,
+) -> anyhow::Result<(ChatStream, Option<String>)> {
+    let stream = TcpStream::connect(address).await?;
+    let mut stream = ChatStream::new(stream);
+    stream.client_hello(CAPABILITIES).await?;
+
+    let mut buffer = [0u8; MSG_LENGTH];
+    let mut pinned = None;
+
+    stream.send_msg(&Msg::NickChange(nick.to_string())).await?;
+
+    match stream.receive_msg(&mut buffer).await {
+        Ok(Msg::ConnectionAccepted) => {
+            KnownHosts::load_default()?.check_unencrypted(address, allow_unencrypted)?;
+            if !password.is_empty() {
+                bail!("Refusing to send a password over an unencrypted connection")
+            }
+            println!("Connected.");
+        }
+        Ok(Msg::ConnectionEncrypted) => {
+            println!("Connected. Encrypting...");
+            let server_key = stream.encrypt_client().await?;
+            let mut known_hosts = KnownHosts::load_default()?;
+            if known_hosts.verify(address, &server_key)? == HostKeyStatus::Pinned {
+                pinned = Some(format!(
+                    "Pinned the identity of {} ({})",
+                    address,
+                    fingerprint(&server_key)
+                ));
+            }
+        }
+        Ok(msg) => bail!("Server refused connection: {}", msg.string()),
+        Err(e) => {
+            bail!("Error connecting to server: {}", e)
+        }
+    }
+
+    if stream.version() < Msg::Authenticated.version() {
+        if !password.is_empty() {
+            bail!("The server is too old to have accounts");
+        }
+        return Ok((stream, pinned));
+    }
+    stream
+        .send_msg(&Msg::Authenticate(password.to_string()))
+        .await?;
+    match stream.receive_msg(&mut buffer).await {
+        Ok(Msg::Authenticated) => Ok((stream, pinned)),
+        Ok(msg) => bail!("Server refused connection: {}", msg.string()),
+        Err(e) => bail!("Error connecting to server: {}", e),
+    }
+}
+
+/// Splits a connected stream into a listener, and a writer task fed by the returned
+/// channel.
+fn start_session(stream: ChatStream) -> (Listen, mpsc::Sender<Msg>) {
+    let keepalive = stream.version() >= Msg::Ping.version();
+    let (reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = mpsc::channel::<Msg>(32);
+
+    tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if writer.send_msg(&msg).await.is_err() {
+                // the listener notices the disconnect too
+                break;
+            }
+        }
+    });
+
+    (Listen::new(reader, keepalive), tx)
+}
+
+fn send(channel: &mpsc::Sender<Msg>, msg: Msg) -> Command<AppMessage> {
+    let channel = channel.clone();
+    // a failed send means the connection is lost, which the listener reports
+    Command::perform(
+        async move { channel.send(msg).await.unwrap_or(()) },
+        AppMessage::Sent,
+    )
+}
+
+/// Returns the password to log in as `nick` with, if it's the user's account.
+fn account_password<'a>(account: &'a Option<(Nick, String)>, nick: &Nick) -> &'a str {
+    match account {
+        Some((account, password)) if account.eq_ignore_ascii_case(nick) => password,
+        _ => "",
+    }
+}
+
+/// Waits for `wait`, then tries to connect again to the address of the login form. The
+/// outcome is tagged with the `generation` of the attempt.
+fn reconnect(
+    login: &LoginState,
+    nick: &Nick,
+    account: &Option<(Nick, String)>,
+    wait: Duration,
+    generation: u64,
+) -> Command<AppMessage> {
+    let address = with_default_port(&login.text_addr_val);
+    let nick = nick.clone();
+    let password = account_password(account, &nick).to_string();
+    let allow_unencrypted = login.allow_unencrypted;
+    Command::perform(
+        async move {
+            sleep(wait).await;
+            let connected = connect(&address, &nick, &password, allow_unencrypted);
+            match timeout(CONNECT_TIMEOUT, connected).await {
+                Ok(Ok((stream, pinned))) => {
+                    let stream = Arc::new(Mutex::new(Some(stream)));
+                    AppMessage::Reconnected(generation, stream, pinned)
+                }
+                Ok(Err(e)) => match e.downcast_ref::<ChatError>() {
+                    // retrying won't make the identity match, or the server encrypt
+                    Some(
+                        ChatError::HostKeyChanged { .. }
+                        | ChatError::EncryptionStripped { .. }
+                        | ChatError::Unencrypted(_),
+                    ) => AppMessage::ReconnectRefused(generation, e.to_string()),
+                    _ => AppMessage::ReconnectFailed(generation, e.to_string()),
+                },
+                Err(_) => AppMessage::ReconnectFailed(generation, "connecting timed out".into()),
+            }
+        },
+        |message| message,
+    )
+}
+
+/// Lists who is in the user's room, beside the chat.
+fn roster_sidebar<'a>(
+    roster: &Roster,
+    state: &'a mut scrollable::State,
+) -> Container<'a, AppMessage> {
+    let title = Text::new(format!("#{} ({})", roster.room(), roster.len()))
+        .size(16)
+        .color([0.5, 0.5, 0.5]);
+    let mut list = Scrollable::new(state)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .spacing(5)
+        .push(title);
+    for nick in roster.nicks() {
+        list = list.push(Text::new(nick.as_str()).size(14));
+    }
+
+    Container::new(list)
+        .width(Length::Units(180))
+        .height(Length::Fill)
+        .padding(10)
+        .style(style::Container::Sidebar)
+}
+
+fn back_to_login(state: &mut button::State, size: u16) -> Button<'_, AppMessage> {
+    Button::new(state, Text::new("Back to login").size(size))
+        .on_press(AppMessage::BackToLogin)
+        .padding(15)
+        .style(style::Button::Simple)
+}
@@ -1,6 +1,8 @@
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use anyhow::Result;
+use chrono::{DateTime, Local};
 use iced::{Alignment, Color, Column, Container, Element, Length, Row, Text};
 
 use crate::style;
@@ -10,9 +12,22 @@ use chat_rs::*;
 pub enum AppMessage {
     AddressChanged(String),
     NickChanged(String),
+    PasswordChanged(String),
+    AllowUnencryptedToggled(bool),
     ButtonPressed,
-    Connected(Arc<Mutex<Option<ChatStream>>>),
+    /// Connected as the nick, with a notice if the server's identity was just pinned.
+    Connected((Arc<Mutex<Option<ChatStream>>>, Nick, Option<String>)),
     ChatMsg(Msg),
+    /// The connection to the server was lost, for the given reason if there is one.
+    Disconnected(Option<String>),
+    /// The server has been quiet for a while, and should be pinged.
+    ServerQuiet,
+    /// The outcome of a reconnect attempt, tagged with the generation of the attempts it
+    /// belongs to. `ReconnectRefused` is a failure that retrying won't fix.
+    Reconnected(u64, Arc<Mutex<Option<ChatStream>>>, Option<String>),
+    ReconnectFailed(u64, String),
+    ReconnectRefused(u64, String),
+    BackToLogin,
     InputChanged(String),
     Send,
     Sent(()),
@@ -35,30 +50,21 @@ pub fn visualise_msg(msg: &Msg) -> Element<'static, AppMessage> {
     use Msg::*;
 
     match msg {
-        NickedUserMsg(nick, message) => {
-            let nick_text = Text::new(nick)
-                .size(14)
-                .color(Color::from_rgb8(248, 47, 58));
-
-            let message_text = Text::new(message).size(14).color(Color::from_rgb8(0, 0, 0));
-
-            let content = Column::new()
-                .align_items(Alignment::Start)
-                .height(Length::Shrink)
-                .width(Length::Shrink)
-                .spacing(10)
-                .padding(10)
-                .push(nick_text)
-                .push(message_text);
-
-            Container::new(content)
-                .height(Length::Shrink)
-                .width(Length::Shrink)
-                .style(style::Container::UserMessage)
-                .into()
+        NickedUserMsg(nick, message) => chat_message(nick.to_string(), message, false),
+        StampedUserMsg(stamp, nick, message) => {
+            let header = format!("{} - {}", nick, format_time(stamp.time, "%H:%M"));
+            chat_message(header, message, false)
+        }
+        HistoryMsg(nick, time, message) => {
+            let header = format!("{} - {}", nick, format_time(*time, "%b %d %H:%M"));
+            chat_message(header, message, true)
+        }
+        StampedHistoryMsg(stamp, nick, message) => {
+            let header = format!("{} - {}", nick, format_time(stamp.time, "%b %d %H:%M"));
+            chat_message(header, message, true)
         }
         NickedNickChange(prev, curr) => {
-            let prev_text = Text::new(prev)
+            let prev_text = Text::new(prev.as_str())
                 .size(14)
                 .color(Color::from_rgb8(248, 47, 58));
             // set font
@@ -68,7 +74,7 @@ pub fn visualise_msg(msg: &Msg) -> Element<'static, AppMessage> {
                 .color(Color::from_rgb8(45, 45, 45));
             // set font
 
-            let curr_text = Text::new(curr)
+            let curr_text = Text::new(curr.as_str())
                 .size(14)
                 .color(Color::from_rgb8(248, 47, 58));
             // set font
@@ -92,15 +98,85 @@ pub fn visualise_msg(msg: &Msg) -> Element<'static, AppMessage> {
 
         NickedConnect(nick) => system_message(nick, " has joined the chat."),
         NickedDisconnect(nick) => system_message(nick, " has left the chat."),
+        NickedJoin(nick, room) => system_message(nick, &format!(" has joined #{}", room)),
+        NickedPart(nick, room) => system_message(nick, &format!(" has left #{}", room)),
 
-        NickedCommand(nick, command) => {
-            system_message(nick, &format!(" executed command: {}", command))
-        }
+        NickedAction(nick, action) => system_message(nick, &format!(" {}", action)),
+        NickedPrivateMsg(nick, message) => private_message(&format!("{} (private)", nick), message),
+        PrivateMsg(nick, message) => private_message(&format!("To {} (private)", nick), message),
+
+        ServerReply(reply) => system_message("", reply),
+        Notice(notice) => system_message("Notice: ", notice),
+        Error(reason) => system_message("Error: ", reason),
 
         _ => system_message("ERROR: UNIMPLEMENTED", ""),
     }
 }
 
+/// Renders a chat message, greyed out if it's replayed from the history.
+fn chat_message(header: String, message: &str, history: bool) -> Element<'static, AppMessage> {
+    let (header_color, message_color, style) = if history {
+        (
+            Color::from_rgb8(150, 90, 95),
+            Color::from_rgb8(90, 90, 90),
+            style::Container::History,
+        )
+    } else {
+        (
+            Color::from_rgb8(248, 47, 58),
+            Color::from_rgb8(0, 0, 0),
+            style::Container::UserMessage,
+        )
+    };
+
+    let header_text = Text::new(header).size(14).color(header_color);
+
+    let message_text = Text::new(message).size(14).color(message_color);
+
+    let content = Column::new()
+        .align_items(Alignment::Start)
+        .height(Length::Shrink)
+        .width(Length::Shrink)
+        .spacing(10)
+        .padding(10)
+        .push(header_text)
+        .push(message_text);
+
+    Container::new(content)
+        .height(Length::Shrink)
+        .width(Length::Shrink)
+        .style(style)
+        .into()
+}
+
+/// Formats the time a message was sent at, in local time.
+fn format_time(time: SystemTime, format: &str) -> String {
+    DateTime::<Local>::from(time).format(format).to_string()
+}
+
+fn private_message(header: &str, message: &str) -> Element<'static, AppMessage> {
+    let header_text = Text::new(header)
+        .size(14)
+        .color(Color::from_rgb8(128, 40, 160));
+
+    let message_text = Text::new(message).size(14).color(Color::from_rgb8(0, 0, 0));
+
+    let content = Column::new()
+        .align_items(Alignment::Start)
+        .height(Length::Shrink)
+        .width(Length::Shrink)
+        .spacing(10)
+        .padding(10)
+        .push(header_text)
+        .push(message_text);
+
+    Container::new(content)
+        .height(Length::Shrink)
+        .width(Length::Shrink)
+        .style(style::Container::Private)
+        .into()
+}
+
 fn system_message(nick: &str, message: &str) -> Element<'static, AppMessage> {
     let nick_text = Text::new(nick)
         .size(14)
@@ -29,6 +29,9 @@ impl button::StyleSheet for Button {
 pub enum Container {
     SystemMessage,
     UserMessage,
+    Private,
+    History,
+    Sidebar,
 }
 
 impl container::StyleSheet for Container {
@@ -36,6 +39,9 @@ impl container::StyleSheet for Container {
         let color = match self {
             Container::SystemMessage => Color::from_rgb8(199, 243, 239),
             Container::UserMessage => Color::from_rgb8(220, 220, 220),
+            Container::Private => Color::from_rgb8(236, 220, 246),
+            Container::History => Color::from_rgb8(236, 236, 236),
+            Container::Sidebar => Color::from_rgb8(242, 242, 242),
         };
 
         container::Style {
@@ -4,11 +4,13 @@ use std::{
     io::{self, prelude::*},
     process,
     sync::{
-        atomic::{AtomicU16, Ordering},
+        atomic::{AtomicBool, AtomicU16, Ordering},
         Arc, Mutex,
     },
+    time::{Duration, SystemTime},
 };
 
+use chrono::{DateTime, Local};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyModifiers},
@@ -16,92 +18,449 @@ use crossterm::{
     style::{self, Attribute, Colorize},
     terminal::{self, ClearType},
 };
+use futures::StreamExt;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::{sleep, timeout, Instant};
 
 use chat_rs::*;
 
 static INPUT_ROWS: AtomicU16 = AtomicU16::new(1);
-
-type Messages = Arc<Mutex<Vec<(String, u16)>>>;
+/// The line between the messages and the input, showing who is in the user's room.
+static ROSTER_LINE: Mutex<String> = Mutex::new(String::new());
+/// Set once the user runs `/quit`, so that the client exits instead of reconnecting.
+static QUITTING: AtomicBool = AtomicBool::new(false);
+/// How long the server may be quiet before it's pinged, and how long it then has to
+/// answer before the connection is considered lost.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(15);
+/// The limits of the wait between reconnection attempts, which doubles after each
+/// failed attempt.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CAPABILITIES: &[Capability] = &[
+    Capability::Encryption,
+    Capability::Fragmentation,
+    Capability::Timestamps,
+];
+
+/// The rendered messages, along with how many lines they take up and their message ID,
+/// if they have one.
+type Messages = Arc<Mutex<Vec<(String, u16, Option<u64>)>>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let address = env::args()
-        .nth(1)
-        .unwrap_or_else(|| prompt_msg("Please input the server IP: ").unwrap());
+    let mut address = None;
+    let mut allow_unencrypted = false;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--allow-unencrypted" => allow_unencrypted = true,
+            _ if address.is_none() => address = Some(arg),
+            _ => {
+                eprintln!("Usage: client_term [--allow-unencrypted] [address]");
+                process::exit(1);
+            }
+        }
+    }
+    let address =
+        address.unwrap_or_else(|| prompt_msg("Please input the server address: ").unwrap());
+    let address = with_default_port(&address);
 
-    println!("Connecting to {}:7878", address);
+    println!("Connecting to {}", address);
 
-    let mut stream = connect_stream(address).await.unwrap_or_else(|err| {
-        eprintln!("Error on connecting: {}", err);
+    let mut stream = open_stream(&address).await.unwrap_or_else(|err| {
+        eprintln!("Error connecting to server: {}", err);
         process::exit(1);
     });
-    let nick = prompt_msg("Enter nickname: ")?;
-
-    let mut buffer = [0u8; MSG_LENGTH];
-
-    stream.send_msg(&Msg::NickChange(nick.clone())).await?;
-
-    match stream.receive_msg(&mut buffer).await {
-        Ok(Msg::ConnectionAccepted) => println!("Connected."),
-        Ok(Msg::ConnectionEncrypted) => {
-            println!("Connected. Encrypting...");
-            stream.encrypt().await?;
+    let nick = loop {
+        match Nick::new(prompt_msg("Enter nickname: ")?) {
+            Ok(nick) => break nick,
+            Err(e) => eprintln!("Invalid nickname: {}", e),
         }
-        Ok(msg) => {
-            eprintln!("Server refused connection: {}", msg.string());
-            process::exit(0)
+    };
+    let password = rpassword::prompt_password("Password (leave empty to join as a guest): ")?;
+
+    match log_in(&mut stream, &nick, &password).await {
+        Ok(None) => {
+            if let Err(e) = check_unencrypted(&address, allow_unencrypted) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
         }
+        Ok(Some(server_key)) => match verify_server(&address, &server_key) {
+            Ok(HostKeyStatus::Trusted) => {}
+            Ok(HostKeyStatus::Pinned) => println!(
+                "Pinned the identity of {} ({})",
+                address,
+                fingerprint(&server_key)
+            ),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
         Err(e) => {
-            println!("Error connecting to server: {}", e);
-            process::exit(0)
+            eprintln!("{}", e);
+            process::exit(0);
         }
     }
+    println!("Connected.");
 
     let messages = Arc::from(Mutex::from(Vec::new()));
-
-    let (reader, writer) = stream.into_split();
+    let (outgoing, outgoing_rx) = mpsc::channel(32);
 
     tokio::spawn({
         let messages = messages.clone();
-        async move { listen(reader, messages).await }
+        let presence = Presence {
+            account: (!password.is_empty()).then(|| (nick.clone(), password)),
+            nick,
+            room: Room::lobby(),
+        };
+        async move {
+            stay_connected(
+                stream,
+                address,
+                allow_unencrypted,
+                presence,
+                messages,
+                outgoing_rx,
+            )
+            .await
+        }
     });
 
-    handle_input(writer, messages).await?;
+    handle_input(outgoing, messages).await?;
     Ok(())
 }
 
-async fn connect_stream(address: String) -> Result<ChatStream, io::Error> {
-    let stream = TcpStream::connect(format!("{}:7878", address)).await?;
-    Ok(ChatStream::new(stream))
+/// Connects to the server and says hello.
+async fn open_stream(address: &str) -> Result<ChatStream, Box<dyn Error>> {
+    let stream = TcpStream::connect(address).await?;
+    let mut stream = ChatStream::new(stream);
+    stream.client_hello(CAPABILITIES).await?;
+    Ok(stream)
 }
 
-async fn listen(mut reader: ChatReaderHalf, messages: Messages) {
+/// Sends the nick to the server, encrypts the stream if the server asks for it, and
+/// logs in with the password (an empty one joins as a guest). Returns the server's
+/// identity key if the stream is encrypted, which still has to be verified.
+async fn log_in(
+    stream: &mut ChatStream,
+    nick: &Nick,
+    password: &str,
+) -> Result<Option<VerifyingKey>, String> {
     let mut buffer = [0u8; MSG_LENGTH];
-    let mut stdout = io::stdout();
+
+    let reply = async {
+        stream.send_msg(&Msg::NickChange(nick.to_string())).await?;
+        stream.receive_msg(&mut buffer).await
+    };
+    let server_key = match reply.await {
+        Ok(Msg::ConnectionAccepted) => None,
+        Ok(Msg::ConnectionEncrypted) => match stream.encrypt_client().await {
+            Ok(server_key) => Some(server_key),
+            Err(e) => return Err(format!("Error encrypting the connection: {}", e)),
+        },
+        Ok(msg) => return Err(format!("Server refused connection: {}", msg.string())),
+        Err(e) => return Err(format!("Error connecting to server: {}", e)),
+    };
+    if server_key.is_none() && !password.is_empty() {
+        return Err("Refusing to send a password over an unencrypted connection.".into());
+    }
+    if stream.version() < Msg::Authenticated.version() {
+        if !password.is_empty() {
+            return Err("The server is too old to have accounts.".into());
+        }
+        return Ok(server_key);
+    }
+
+    let reply = async {
+        stream
+            .send_msg(&Msg::Authenticate(password.to_string()))
+            .await?;
+        stream.receive_msg(&mut buffer).await
+    };
+    match reply.await {
+        Ok(Msg::Authenticated) => Ok(server_key),
+        Ok(msg) => Err(format!("Server refused connection: {}", msg.string())),
+        Err(e) => Err(format!("Error connecting to server: {}", e)),
+    }
+}
+
+/// Checks the server's identity against the known hosts file, pinning it on first use.
+fn verify_server(host: &str, server_key: &VerifyingKey) -> Result<HostKeyStatus, ChatError> {
+    KnownHosts::load_default().and_then(|mut hosts| hosts.verify(host, server_key))
+}
+
+/// Checks whether the server may be talked to unencrypted, which needs the user's
+/// permission, and is never the case once its identity is pinned.
+fn check_unencrypted(host: &str, allowed: bool) -> Result<(), ChatError> {
+    KnownHosts::load_default().and_then(|hosts| hosts.check_unencrypted(host, allowed))
+}
+
+/// Who the user is on the server, and where, so that it can be restored after
+/// reconnecting.
+struct Presence {
+    nick: Nick,
+    room: Room,
+    /// The registered nick the user logged in with or registered, and its password.
+    account: Option<(Nick, String)>,
+}
+
+impl Presence {
+    /// Keeps track of the user's own nick changes and room moves.
+    fn update(&mut self, msg: &Msg) {
+        match msg {
+            Msg::NickedNickChange(old, new) if *old == self.nick => self.nick = new.clone(),
+            Msg::NickedJoin(nick, room) if *nick == self.nick => self.room = room.clone(),
+            _ => {}
+        }
+    }
+
+    /// The password to log in with, if the user's current nick is their account.
+    fn password(&self) -> &str {
+        match &self.account {
+            Some((account, password)) if account.eq_ignore_ascii_case(&self.nick) => password,
+            _ => "",
+        }
+    }
+}
+
+/// Runs the connection to the server, and reconnects whenever it's lost until the user
+/// quits.
+async fn stay_connected(
+    mut stream: ChatStream,
+    address: String,
+    allow_unencrypted: bool,
+    mut presence: Presence,
+    messages: Messages,
+    mut outgoing: Receiver<Msg>,
+) {
+    loop {
+        let reason = listen(stream, &mut presence, &messages, &mut outgoing).await;
+        set_roster_line(None);
+        if QUITTING.load(Ordering::SeqCst) {
+            exit(reason);
+        }
+        show_status(
+            &match reason {
+                None => "Disconnected from server.".to_string(),
+                Some(reason) => format!("Disconnected from server: {}", reason),
+            },
+            &messages,
+        );
+
+        stream = reconnect(
+            &address,
+            allow_unencrypted,
+            &presence,
+            &messages,
+            &mut outgoing,
+        )
+        .await;
+        show_status("Reconnected.", &messages);
+        if presence.room != Room::lobby() {
+            let rejoin = Msg::JoinRoom(presence.room.clone());
+            stream.send_msg(&rejoin).await.unwrap_or(()); // a failure is noticed by listen
+        }
+    }
+}
+
+/// Connects to the server again, waiting longer after every failed attempt. Messages
+/// typed in the meantime are dropped.
+async fn reconnect(
+    address: &str,
+    allow_unencrypted: bool,
+    presence: &Presence,
+    messages: &Messages,
+    outgoing: &mut Receiver<Msg>,
+) -> ChatStream {
+    let mut backoff = MIN_BACKOFF;
     loop {
-        let msg = match reader.receive_msg(&mut buffer).await {
-            Err(_) => {
-                execute!(stdout, terminal::LeaveAlternateScreen).unwrap();
-                terminal::disable_raw_mode().unwrap();
-                println!("Disconnected from server.");
-                process::exit(0);
+        show_status(
+            &format!("Reconnecting in {}s...", backoff.as_secs()),
+            messages,
+        );
+        let wait = sleep(backoff);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                () = &mut wait => break,
+                Some(_) = outgoing.recv() => show_status("Not connected, message not sent.", messages),
             }
-            Ok(msg) => msg,
+        }
+
+        let attempt = async {
+            let mut stream = open_stream(address).await.map_err(|e| e.to_string())?;
+            let server_key = log_in(&mut stream, &presence.nick, presence.password()).await?;
+            Ok::<_, String>((stream, server_key))
         };
+        match timeout(CONNECT_TIMEOUT, attempt).await {
+            Ok(Ok((stream, None))) => match check_unencrypted(address, allow_unencrypted) {
+                Ok(()) => return stream,
+                // retrying won't make the server encrypt
+                Err(e) => exit(Some(e.to_string())),
+            },
+            Ok(Ok((stream, Some(server_key)))) => match verify_server(address, &server_key) {
+                Ok(_) => return stream,
+                // retrying won't make the identity match
+                Err(e) => exit(Some(e.to_string())),
+            },
+            Ok(Err(e)) => show_status(&e, messages),
+            Err(_) => show_status("Connecting timed out.", messages),
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Shows the server's messages and sends the user's, until the connection is lost.
+/// Returns the reason it was lost, if there is one.
+async fn listen(
+    stream: ChatStream,
+    presence: &mut Presence,
+    messages: &Messages,
+    outgoing: &mut Receiver<Msg>,
+) -> Option<String> {
+    let mut stdout = io::stdout();
+    let keepalive = stream.version() >= Msg::Ping.version();
+    let (reader, mut writer) = stream.into_split();
+    let mut incoming = reader.into_framed();
+    // the server is pinged when it has been quiet for a while, to notice when the
+    // connection silently died, e.g. after the network changed (unless it's too old
+    // to answer)
+    let quiet = sleep(PING_INTERVAL);
+    tokio::pin!(quiet);
+    let mut pinged = false;
+    let mut roster = Roster::default();
+
+    loop {
+        tokio::select! {
+            msg = incoming.next() => {
+                quiet.as_mut().reset(Instant::now() + PING_INTERVAL);
+                pinged = false;
+                match msg {
+                    Some(Ok(Msg::ConnectionClosed(reason))) => break Some(strip_control(&reason)),
+                    // reconnecting would only get the user kicked again
+                    Some(Ok(Msg::Kicked(reason))) => exit(Some(strip_control(&reason))),
+                    Some(Ok(Msg::ServerShutdown(reason))) => {
+                        break Some(shutdown_reason(&strip_control(&reason)))
+                    }
+                    Some(Ok(Msg::Ping)) => {
+                        if let Err(e) = writer.send_msg(&Msg::Pong).await {
+                            break Some(e.to_string());
+                        }
+                    }
+                    Some(Ok(Msg::Pong)) => {}
+                    Some(Ok(msg)) => {
+                        presence.update(&msg);
+                        if roster.update(&msg) {
+                            set_roster_line(Some(&roster));
+                        }
+                        if !matches!(msg, Msg::Roster(_, _)) {
+                            add_message(msg, messages);
+                        }
+                        draw_messages(messages, &mut stdout).unwrap();
+                    }
+                    Some(Err(ChatError::Closed)) | None => break None,
+                    Some(Err(e)) => break Some(e.to_string()),
+                }
+            }
+            Some(msg) = outgoing.recv() => {
+                if let Err(e) = writer.send_msg(&msg).await {
+                    break Some(e.to_string());
+                }
+                // assume the registration works; if it doesn't, the nick isn't
+                // registered and the password is ignored when reconnecting
+                match msg {
+                    Msg::Register(password) if presence.password().is_empty() => {
+                        presence.account = Some((presence.nick.clone(), password));
+                    }
+                    _ => {}
+                }
+            }
+            () = &mut quiet, if keepalive => {
+                if pinged {
+                    break Some("the server stopped responding".into());
+                }
+                if let Err(e) = writer.send_msg(&Msg::Ping).await {
+                    break Some(e.to_string());
+                }
+                pinged = true;
+                quiet.as_mut().reset(Instant::now() + PING_TIMEOUT);
+            }
+        }
+    }
+}
+
+/// Describes the server shutting down, with its reason if it gave one.
+fn shutdown_reason(reason: &str) -> String {
+    match reason {
+        "" => "the server is shutting down".to_string(),
+        reason => format!("the server is shutting down: {}", reason),
+    }
+}
 
-        add_message(msg, &messages);
-        draw_messages(&messages, &mut stdout).unwrap();
+/// Leaves the UI and exits, telling the user why the connection was closed.
+fn exit(reason: Option<String>) -> ! {
+    execute!(io::stdout(), terminal::LeaveAlternateScreen).unwrap();
+    terminal::disable_raw_mode().unwrap();
+    match reason {
+        None => println!("Disconnected from server."),
+        Some(reason) => println!("Disconnected from server: {}", reason),
     }
+    process::exit(0);
+}
+
+/// Adds a status line from the client itself, and redraws the messages.
+fn show_status(status: &str, messages: &Messages) {
+    push_line(
+        format!("! {}", status).dark_yellow().to_string(),
+        None,
+        messages,
+    );
+    draw_messages(messages, &mut io::stdout()).unwrap();
+}
+
+/// Shows who is in the user's room on the next redraw, or nobody if `roster` is `None`,
+/// e.g. while disconnected.
+fn set_roster_line(roster: Option<&Roster>) {
+    *ROSTER_LINE.lock().unwrap() = match roster {
+        Some(roster) => {
+            let nicks: Vec<&str> = roster.nicks().map(Nick::as_str).collect();
+            format!(
+                "#{} ({}): {}",
+                roster.room(),
+                roster.len(),
+                nicks.join(", ")
+            )
+        }
+        None => String::new(),
+    };
 }
 
 /// Adds a message to the messages vector while keeping it small by removing old messages.
 fn add_message(msg: Msg, messages: &Messages) {
+    // a stamped message may be received twice, e.g. as history after rejoining a room
+    // or reconnecting
+    let id = msg.stamp().map(|stamp| stamp.id);
+    if id.is_some()
+        && messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, _, seen)| *seen == id)
+    {
+        return;
+    }
+    push_line(stringify_message(msg), id, messages);
+}
+
+fn push_line(string: String, id: Option<u64>, messages: &Messages) {
     let mut messages = messages.lock().unwrap();
-    let string = stringify_message(msg);
     let lines = get_line_amount(&string);
 
-    messages.push((string, lines));
+    messages.push((string, lines, id));
 
     let (_, y) = terminal::size().unwrap();
     let maxlen = 2 * (y - INPUT_ROWS.load(Ordering::SeqCst)); // x2 so that messages behave better on-screen
@@ -116,7 +475,29 @@ fn stringify_message(msg: Msg) -> String {
     use Attribute::Bold;
     use Msg::*;
     match msg {
-        NickedUserMsg(nick, message) => format!("{}> {}", nick.red().attribute(Bold), message),
+        NickedUserMsg(nick, message) => format!(
+            "{}> {}",
+            nick.red().attribute(Bold),
+            strip_control(&message)
+        ),
+        StampedUserMsg(stamp, nick, message) => format!(
+            "{} {}> {}",
+            format_time(stamp.time, "[%H:%M]").dark_grey(),
+            nick.red().attribute(Bold),
+            strip_control(&message)
+        ),
+        HistoryMsg(nick, time, message) => format!(
+            "{} {}> {}",
+            format_time(time, "[%b %d %H:%M]").dark_grey(),
+            nick.dark_red(),
+            strip_control(&message).dark_grey()
+        ),
+        StampedHistoryMsg(stamp, nick, message) => format!(
+            "{} {}> {}",
+            format_time(stamp.time, "[%b %d %H:%M]").dark_grey(),
+            nick.dark_red(),
+            strip_control(&message).dark_grey()
+        ),
         NickedNickChange(prev, curr) => format!(
             "! {} has changed their nickname to {}",
             prev.red().attribute(Bold),
@@ -125,19 +506,49 @@ fn stringify_message(msg: Msg) -> String {
 
         NickedConnect(nick) => format!("! {} has joined the chat.", nick.red().attribute(Bold)),
         NickedDisconnect(nick) => format!("! {} has left the chat.", nick.red().attribute(Bold)),
+        NickedJoin(nick, room) => format!("! {} has joined #{}", nick.red().attribute(Bold), room),
+        NickedPart(nick, room) => format!("! {} has left #{}", nick.red().attribute(Bold), room),
 
-        NickedCommand(nick, command) => format!(
-            "! {} executed {} (to be implemented properly with the command system)",
+        NickedAction(nick, action) => format!(
+            "* {} {}",
+            nick.red().attribute(Bold),
+            strip_control(&action)
+        ),
+        NickedPrivateMsg(nick, message) => format!(
+            "{} {}> {}",
+            "[private]".magenta().attribute(Bold),
+            nick.red().attribute(Bold),
+            strip_control(&message).magenta()
+        ),
+        // Our own private messages, echoed locally
+        PrivateMsg(nick, message) => format!(
+            "{} -> {}> {}",
+            "[private]".magenta().attribute(Bold),
             nick.red().attribute(Bold),
-            command
+            strip_control(&message).magenta()
         ),
 
+        ServerReply(reply) => format!("- {}", strip_control(&reply)),
+        Notice(notice) => format!("! {}", strip_control(&notice).yellow()),
+        Error(reason) => format!("! {}", strip_control(&reason).red()),
+
         _ => "???? (this shouldn't have been received by the client!)"
             .blue()
             .to_string(),
     }
 }
 
+/// Formats the time a message was sent at, in local time.
+fn format_time(time: SystemTime, format: &str) -> String {
+    DateTime::<Local>::from(time).format(format).to_string()
+}
+
+/// Removes control characters from text sent by other users, so that they can't
+/// inject terminal escape sequences.
+fn strip_control(string: &str) -> String {
+    string.chars().filter(|c| !c.is_control()).collect()
+}
+
 fn get_line_amount(string: &str) -> u16 {
     let (x, _) = terminal::size().unwrap();
     let mut output = 0;
@@ -150,7 +561,7 @@ fn get_line_amount(string: &str) -> u16 {
 
 fn draw_messages(messages: &Messages, stdout: &mut io::Stdout) -> Result<(), Box<dyn Error>> {
     let messages = messages.lock().unwrap();
-    let (_, y) = terminal::size()?;
+    let (x, y) = terminal::size()?;
     let allowed_rows = y - INPUT_ROWS.load(Ordering::SeqCst) - 1;
     let fits = {
         let mut count = 0;
@@ -170,6 +581,14 @@ fn draw_messages(messages: &Messages, stdout: &mut io::Stdout) -> Result<(), Box
         cursor::SavePosition,
         cursor::MoveTo(0, allowed_rows),
         terminal::Clear(ClearType::FromCursorUp),
+        terminal::Clear(ClearType::CurrentLine),
+    )?;
+    // nicks and room names are ASCII, so the line can be cut anywhere
+    let roster_line = ROSTER_LINE.lock().unwrap();
+    let roster_line = &roster_line[..roster_line.len().min(x.into())];
+    queue!(
+        stdout,
+        style::Print(roster_line.dark_grey()),
         cursor::MoveTo(0, 0)
     )?;
     for tuple in to_print {
@@ -182,10 +601,7 @@ fn draw_messages(messages: &Messages, stdout: &mut io::Stdout) -> Result<(), Box
     Ok(())
 }
 
-async fn handle_input(
-    mut writer: ChatWriterHalf,
-    messages: Messages,
-) -> Result<(), Box<dyn Error>> {
+async fn handle_input(outgoing: Sender<Msg>, messages: Messages) -> Result<(), Box<dyn Error>> {
     let mut stdout = io::stdout();
 
     terminal::enable_raw_mode()?;
@@ -200,7 +616,7 @@ async fn handle_input(
         let event = event::read()?;
         if let Event::Key(event) = event {
             let do_break =
-                handle_key_event(event, &mut string, &mut writer, &mut stdout, &messages).await?;
+                handle_key_event(event, &mut string, &outgoing, &mut stdout, &messages).await?;
 
             if do_break {
                 break;
@@ -218,7 +634,7 @@ async fn handle_input(
 async fn handle_key_event(
     event: event::KeyEvent,
     string: &mut String,
-    writer: &mut ChatWriterHalf,
+    outgoing: &Sender<Msg>,
     stdout: &mut io::Stdout,
     messages: &Messages,
 ) -> Result<bool, Box<dyn Error>> {
@@ -228,7 +644,15 @@ async fn handle_key_event(
         return Ok(true);
     } else if event.code == KeyCode::Enter {
         if !string.is_empty() {
-            writer.send_msg(&Msg::UserMsg(string.clone())).await?;
+            let msg = Msg::from_input(string);
+            if matches!(&msg, Msg::Command(command) if command.trim().eq_ignore_ascii_case("quit"))
+            {
+                QUITTING.store(true, Ordering::SeqCst);
+            }
+            if let Msg::PrivateMsg(_, _) = msg {
+                add_message(msg.clone(), messages);
+            }
+            outgoing.send(msg).await?;
             string.clear();
             queue!(stdout, terminal::Clear(ClearType::FromCursorUp))?;
         }
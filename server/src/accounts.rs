@@ -0,0 +1,126 @@
+//! Registered accounts, kept in a JSON file of nicks and their password hashes.
+//!
+//! Passwords are hashed with Argon2id. Hashing is deliberately slow and memory-hard,
+//! so `hash_password` and `verify_password` should be run off the async runtime, e.g.
+//! with `tokio::task::spawn_blocking`.
+//!
+//! Nicks are registered regardless of their case: once `Bob` is registered, nobody else
+//! can use `bob` either.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use log::warn;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+use chat_rs::Nick;
+
+use crate::files;
+
+/// The shortest password accepted for a new account.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Account {
+    /// The nick as it was registered.
+    nick: String,
+    /// The password hash, as a PHC string.
+    hash: String,
+    registered: DateTime<Utc>,
+}
+
+pub struct Accounts {
+    path: PathBuf,
+    /// The accounts, by their lowercased nick.
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    /// Loads the accounts from the file at `path`, which is created when the first
+    /// account is registered. Accounts with invalid nicks are skipped.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let accounts: HashMap<String, Account> = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        let accounts = accounts
+            .into_values()
+            .filter(|account| match Nick::new(account.nick.as_str()) {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("Skipping the account of {:?}: {}", account.nick, e);
+                    false
+                }
+            })
+            .map(|account| (account.nick.to_ascii_lowercase(), account))
+            .collect();
+        Ok(Accounts {
+            path: path.to_path_buf(),
+            accounts,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_registered(&self, nick: &str) -> bool {
+        self.accounts.contains_key(&nick.to_ascii_lowercase())
+    }
+
+    /// Returns the password hash of `nick`'s account, if it's registered.
+    pub fn password_hash(&self, nick: &str) -> Option<String> {
+        let account = self.accounts.get(&nick.to_ascii_lowercase())?;
+        Some(account.hash.clone())
+    }
+
+    /// Registers `nick` with an already hashed password, and saves the accounts.
+    pub fn register(&mut self, nick: &Nick, hash: String) -> io::Result<()> {
+        let account = Account {
+            nick: nick.to_string(),
+            hash,
+            registered: Utc::now(),
+        };
+        let key = nick.to_ascii_lowercase();
+        self.accounts.insert(key.clone(), account);
+        if let Err(e) = self.save() {
+            // it would be lost on restart
+            self.accounts.remove(&key);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        files::replace_json(&self.path, &self.accounts)
+    }
+}
+
+/// Returns whether `nick` belongs to the same account as `account`.
+pub fn same_account(account: &Nick, nick: &str) -> bool {
+    account.eq_ignore_ascii_case(nick)
+}
+
+/// Hashes a new password with a random salt.
+pub fn hash_password(password: &str) -> password_hash::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Checks a password against a hash made by `hash_password`.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
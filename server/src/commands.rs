@@ -0,0 +1,488 @@
+//! Slash commands, sent by clients as `Msg::Command` and run by the server.
+//!
+//! Commands don't touch any server state themselves. A command's handler looks at
+//! who called it and returns a list of `Action`s, which the caller's connection then
+//! carries out.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::Duration;
+
+use chat_rs::{Msg, Nick, Room};
+
+use crate::moderation::{self, BanTarget};
+
+/// Who is running a command, and what they can see.
+pub struct Caller<'a> {
+    pub nick: &'a Nick,
+    pub room: &'a Room,
+    pub is_operator: bool,
+    /// Everyone currently connected, the caller included, and the room they're in.
+    pub online: &'a HashMap<Nick, Room>,
+}
+
+/// Something the server should do as a result of a command.
+#[derive(Debug)]
+pub enum Action {
+    /// Sends a `ServerReply` to the caller only.
+    Reply(String),
+    /// Sends an `Error` to the caller only.
+    Error(String),
+    /// Sends a message to everyone in the caller's room.
+    Broadcast(Msg),
+    SendTo(Nick, Msg),
+    ChangeNick(String),
+    /// Moves the caller into another room.
+    JoinRoom(Room),
+    /// Registers the caller's nick with the given password.
+    Register(String),
+    /// Disconnects a user, with a reason.
+    Kick(Nick, String),
+    /// Bans a nick or an address, for the given time or permanently, with a reason.
+    Ban(BanTarget, Option<Duration>, String),
+    Unban(BanTarget),
+    ListBans,
+    /// Mutes a user for the given time, or until they're unmuted.
+    Mute(Nick, Option<Duration>),
+    Unmute(Nick),
+    /// Makes a user an operator or not, until they disconnect.
+    SetOperator(Nick, bool),
+    /// Disconnects the caller.
+    Quit,
+}
+
+enum CommandError {
+    /// The arguments don't match the command's usage.
+    Usage,
+    Failed(String),
+}
+
+type Handler = fn(&Caller, &str) -> Result<Vec<Action>, CommandError>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// Whether only server operators may run the command.
+    pub operator_only: bool,
+    handler: Handler,
+}
+
+/// The registry of every command the server knows.
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "/help [command]",
+        help: "lists the available commands, or explains one of them",
+        operator_only: false,
+        handler: help,
+    },
+    Command {
+        name: "who",
+        usage: "/who",
+        help: "lists the users in your room",
+        operator_only: false,
+        handler: who,
+    },
+    Command {
+        name: "me",
+        usage: "/me <action>",
+        help: "describes what you're doing, e.g. /me waves",
+        operator_only: false,
+        handler: me,
+    },
+    Command {
+        name: "msg",
+        usage: "/msg <nick> <message>",
+        help: "sends a private message",
+        operator_only: false,
+        handler: msg,
+    },
+    Command {
+        name: "join",
+        usage: "/join <room>",
+        help: "moves you into a room, creating it if nobody is in it",
+        operator_only: false,
+        handler: join,
+    },
+    Command {
+        name: "part",
+        usage: "/part",
+        help: "moves you back into the lobby",
+        operator_only: false,
+        handler: part,
+    },
+    Command {
+        name: "rooms",
+        usage: "/rooms",
+        help: "lists the rooms that have users in them",
+        operator_only: false,
+        handler: rooms,
+    },
+    Command {
+        name: "nick",
+        usage: "/nick <nick>",
+        help: "changes your nick",
+        operator_only: false,
+        handler: nick,
+    },
+    Command {
+        name: "register",
+        usage: "/register <password>",
+        help: "registers your nick, so that only you can use it",
+        operator_only: false,
+        handler: register,
+    },
+    Command {
+        name: "kick",
+        usage: "/kick <nick> [reason]",
+        help: "disconnects a user",
+        operator_only: true,
+        handler: kick,
+    },
+    Command {
+        name: "ban",
+        usage: "/ban <nick|address|network> [duration] [reason]",
+        help: "bans a nick, an address or a network like 10.0.0.0/8, for a duration like 12h or 7d or for good",
+        operator_only: true,
+        handler: ban,
+    },
+    Command {
+        name: "unban",
+        usage: "/unban <nick|address|network>",
+        help: "lifts a ban",
+        operator_only: true,
+        handler: unban,
+    },
+    Command {
+        name: "bans",
+        usage: "/bans",
+        help: "lists the bans",
+        operator_only: true,
+        handler: bans,
+    },
+    Command {
+        name: "mute",
+        usage: "/mute <nick> [duration]",
+        help: "keeps a user from talking, for a duration like 10m or until they're unmuted",
+        operator_only: true,
+        handler: mute,
+    },
+    Command {
+        name: "unmute",
+        usage: "/unmute <nick>",
+        help: "lets a muted user talk again",
+        operator_only: true,
+        handler: unmute,
+    },
+    Command {
+        name: "op",
+        usage: "/op <nick>",
+        help: "makes a user an operator until they disconnect",
+        operator_only: true,
+        handler: op,
+    },
+    Command {
+        name: "deop",
+        usage: "/deop <nick>",
+        help: "takes a user's operator status away",
+        operator_only: true,
+        handler: deop,
+    },
+    Command {
+        name: "quit",
+        usage: "/quit",
+        help: "disconnects from the server",
+        operator_only: false,
+        handler: quit,
+    },
+];
+
+/// Parses and runs a command line (without its leading `/`), returning what should
+/// be done about it. Unknown commands, missing permissions and bad arguments all
+/// result in a single `Action::Error`.
+pub fn dispatch(caller: &Caller, line: &str) -> Vec<Action> {
+    let line = line.trim();
+    let (name, args) = split_word(line);
+
+    let command = match find(name) {
+        Some(command) if command.operator_only && !caller.is_operator => {
+            return vec![Action::Error(format!(
+                "/{} is only available to operators",
+                command.name
+            ))]
+        }
+        Some(command) => command,
+        None => {
+            return vec![Action::Error(format!(
+                "unknown command /{}, try /help",
+                name
+            ))]
+        }
+    };
+
+    match (command.handler)(caller, args) {
+        Ok(actions) => actions,
+        Err(CommandError::Usage) => vec![Action::Error(format!("usage: {}", command.usage))],
+        Err(CommandError::Failed(reason)) => vec![Action::Error(reason)],
+    }
+}
+
+fn find(name: &str) -> Option<&'static Command> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    COMMANDS
+        .iter()
+        .find(|command| command.name.eq_ignore_ascii_case(name))
+}
+
+/// Splits off the first whitespace-separated word of `args`, returning it and the
+/// (trimmed) rest.
+fn split_word(args: &str) -> (&str, &str) {
+    match args.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (args, ""),
+    }
+}
+
+fn help(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if !args.is_empty() {
+        let command = find(args)
+            .filter(|command| !command.operator_only || caller.is_operator)
+            .ok_or_else(|| CommandError::Failed(format!("unknown command /{}", args)))?;
+        return Ok(vec![Action::Reply(format!(
+            "{} - {}",
+            command.usage, command.help
+        ))]);
+    }
+
+    let mut actions = vec![Action::Reply("Available commands:".into())];
+    for command in COMMANDS {
+        if !command.operator_only || caller.is_operator {
+            actions.push(Action::Reply(format!(
+                "  {} - {}",
+                command.usage, command.help
+            )));
+        }
+    }
+    actions.push(Action::Reply(
+        "Start a message with // to send it as a message starting with /.".into(),
+    ));
+    Ok(actions)
+}
+
+fn who(caller: &Caller, _args: &str) -> Result<Vec<Action>, CommandError> {
+    let mut members: Vec<&str> = caller
+        .online
+        .iter()
+        .filter(|(_, room)| *room == caller.room)
+        .map(|(nick, _)| nick.as_str())
+        .collect();
+    members.sort_unstable();
+    Ok(vec![Action::Reply(format!(
+        "{} in #{}: {}",
+        members.len(),
+        caller.room,
+        members.join(", ")
+    ))])
+}
+
+fn me(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::Broadcast(Msg::NickedAction(
+        caller.nick.clone(),
+        args.to_string(),
+    ))])
+}
+
+fn msg(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    let (to, text) = split_word(args);
+    if to.is_empty() || text.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![private_msg(caller, to, text)])
+}
+
+/// Delivers a private message from the caller to `to`, or errors if nobody by that
+/// nick is online.
+pub fn private_msg(caller: &Caller, to: &str, text: &str) -> Action {
+    match online_nick(caller.online, to) {
+        Some(to) => Action::SendTo(
+            to.clone(),
+            Msg::NickedPrivateMsg(caller.nick.clone(), text.to_string()),
+        ),
+        None => Action::Error(format!("{} is not online", to)),
+    }
+}
+
+fn join(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    let room = Room::new(args).map_err(|e| CommandError::Failed(e.to_string()))?;
+    Ok(join_room(caller, room))
+}
+
+/// Moves the caller into `room`, unless they're already in it.
+pub fn join_room(caller: &Caller, room: Room) -> Vec<Action> {
+    if room == *caller.room {
+        return vec![Action::Error(format!("you are already in #{}", room))];
+    }
+    vec![Action::JoinRoom(room)]
+}
+
+fn part(caller: &Caller, _args: &str) -> Result<Vec<Action>, CommandError> {
+    Ok(part_room(caller))
+}
+
+/// Moves the caller back into the lobby.
+pub fn part_room(caller: &Caller) -> Vec<Action> {
+    if *caller.room == Room::lobby() {
+        return vec![Action::Error("you are already in the lobby".into())];
+    }
+    vec![Action::JoinRoom(Room::lobby())]
+}
+
+fn rooms(caller: &Caller, _args: &str) -> Result<Vec<Action>, CommandError> {
+    Ok(list_rooms(caller))
+}
+
+/// Replies with every room that has users in it, and how many. The lobby is always
+/// listed.
+pub fn list_rooms(caller: &Caller) -> Vec<Action> {
+    let mut rooms = BTreeMap::from([(Room::lobby(), 0)]);
+    for room in caller.online.values() {
+        *rooms.entry(room.clone()).or_insert(0) += 1;
+    }
+    let rooms: Vec<String> = rooms
+        .iter()
+        .map(|(room, users)| format!("#{} ({})", room, users))
+        .collect();
+    vec![Action::Reply(format!("Rooms: {}", rooms.join(", ")))]
+}
+
+fn nick(_caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::ChangeNick(args.to_string())])
+}
+
+fn register(_caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::Register(args.to_string())])
+}
+
+fn quit(_caller: &Caller, _args: &str) -> Result<Vec<Action>, CommandError> {
+    Ok(vec![Action::Quit])
+}
+
+/// Returns the nick of whoever is online as `nick`, regardless of case, since nicks
+/// that only differ in case can't be online at the same time.
+pub fn online_nick<'a, V>(online: &'a HashMap<Nick, V>, nick: &str) -> Option<&'a Nick> {
+    online
+        .keys()
+        .find(|online| online.eq_ignore_ascii_case(nick))
+}
+
+/// Finds someone who is online by their nick, who mustn't be the caller.
+fn online_other(caller: &Caller, nick: &str) -> Result<Nick, CommandError> {
+    let nick = match online_nick(caller.online, nick) {
+        Some(nick) => nick.clone(),
+        None => return Err(CommandError::Failed(format!("{} is not online", nick))),
+    };
+    if nick == *caller.nick {
+        return Err(CommandError::Failed("you can't do that to yourself".into()));
+    }
+    Ok(nick)
+}
+
+/// Splits off an optional duration at the start of `args`, returning it and the rest.
+fn split_duration(args: &str) -> Result<(Option<Duration>, &str), CommandError> {
+    let (first, rest) = split_word(args);
+    if first.is_empty() || !first.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok((None, args));
+    }
+    match moderation::parse_duration(first) {
+        Some(duration) => Ok((Some(duration), rest)),
+        None => Err(CommandError::Failed(format!(
+            "{} is not a duration of up to {}, try e.g. 30m, 12h or 7d",
+            first,
+            moderation::format_duration(moderation::MAX_DURATION)
+        ))),
+    }
+}
+
+fn kick(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    let (nick, reason) = split_word(args);
+    if nick.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    let nick = online_other(caller, nick)?;
+    Ok(vec![Action::Kick(nick, reason.to_string())])
+}
+
+fn ban(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    let (target, rest) = split_word(args);
+    if target.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    let target = BanTarget::parse(target).map_err(CommandError::Failed)?;
+    if target == BanTarget::Nick(caller.nick.to_ascii_lowercase()) {
+        return Err(CommandError::Failed("you can't do that to yourself".into()));
+    }
+    let (duration, reason) = split_duration(rest)?;
+    Ok(vec![Action::Ban(target, duration, reason.to_string())])
+}
+
+fn unban(_caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    let target = BanTarget::parse(args).map_err(CommandError::Failed)?;
+    Ok(vec![Action::Unban(target)])
+}
+
+fn bans(_caller: &Caller, _args: &str) -> Result<Vec<Action>, CommandError> {
+    Ok(vec![Action::ListBans])
+}
+
+fn mute(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    let (nick, rest) = split_word(args);
+    if nick.is_empty() || rest.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    let nick = online_other(caller, nick)?;
+    let (duration, _) = split_duration(rest)?;
+    if duration.is_none() && !rest.is_empty() {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::Mute(nick, duration)])
+}
+
+fn unmute(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::Unmute(online_other(caller, args)?)])
+}
+
+fn op(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::SetOperator(online_other(caller, args)?, true)])
+}
+
+fn deop(caller: &Caller, args: &str) -> Result<Vec<Action>, CommandError> {
+    if args.is_empty() || args.contains(char::is_whitespace) {
+        return Err(CommandError::Usage);
+    }
+    Ok(vec![Action::SetOperator(
+        online_other(caller, args)?,
+        false,
+    )])
+}
@@ -0,0 +1,382 @@
+//! The server's configuration, read from an optional TOML file and overridden
+//! by command line options.
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use thiserror::Error;
+
+use chat_rs::{Nick, RekeyPolicy, DEFAULT_PORT, MSG_LENGTH};
+
+/// The longest timeout or mute that can be configured, a day. Much longer ones would
+/// overflow the clock.
+const MAX_SECONDS: u64 = 24 * 60 * 60;
+
+/// The longest the history can be kept, about a hundred years.
+const MAX_AGE_DAYS: i64 = 36_500;
+
+/// A server for the chat-rs protocol.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// The IP address to listen on [default: 0.0.0.0]
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// The port to listen on [default: 7878]
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// The maximum number of connected users [default: 50]
+    #[arg(long)]
+    pub max_users: Option<usize>,
+    /// A TOML config file; command line options take precedence over it
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// The log level: off, error, warn, info, debug or trace. Takes precedence over
+    /// `RUST_LOG`, which takes precedence over the config file
+    #[arg(long)]
+    pub log_level: Option<LevelFilter>,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("couldn't read {}: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("couldn't parse {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub max_users: usize,
+    pub log_level: LevelFilter,
+    /// The size limit for messages reassembled from fragments.
+    pub max_message_size: usize,
+    /// How many messages can be waiting to be sent to a user before they're
+    /// disconnected for not keeping up.
+    pub outbound_queue_size: usize,
+    /// How many seconds a connection may be quiet before it's pinged.
+    pub heartbeat_interval: u64,
+    /// How many seconds a connection may be quiet before it's closed.
+    pub idle_timeout: u64,
+    /// How many seconds a new connection has to finish the handshake and send its nick.
+    pub handshake_timeout: u64,
+    /// How many seconds the connections have to send what's queued on shutdown.
+    pub shutdown_timeout: u64,
+    /// The reason sent to the users when the server shuts down, if not empty.
+    pub shutdown_message: String,
+    /// The addresses whose users are allowed to run operator-only commands, once logged
+    /// in to an account.
+    pub operators: Vec<IpAddr>,
+    /// The registered nicks allowed to run operator-only commands, once logged in.
+    pub operator_accounts: Vec<String>,
+    /// The file the bans are kept in.
+    pub bans_path: PathBuf,
+    pub encryption: EncryptionConfig,
+    pub history: HistoryConfig,
+    pub accounts: AccountsConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// Whether the server only accepts encrypted connections, or only unencrypted ones.
+    pub enabled: bool,
+    /// The server's identity key, which is generated on first run.
+    pub identity_key: PathBuf,
+    pub rekey_after_frames: u64,
+    pub rekey_after_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Whether chat messages are recorded and replayed to users joining a room.
+    pub enabled: bool,
+    /// The log file the history is kept in.
+    pub path: PathBuf,
+    /// How many of a room's latest messages are replayed to a user joining it.
+    pub replay: usize,
+    /// How many messages are kept per room.
+    pub max_messages: usize,
+    /// How many days messages are kept for, or 0 to keep them until `max_messages`
+    /// pushes them out.
+    pub max_age_days: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// Whether users can register their nicks. Accounts are only available in
+    /// encrypted mode, since passwords would be sent in the clear otherwise.
+    pub enabled: bool,
+    /// The file the accounts and their password hashes are kept in.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// How many messages per second a user may send on average.
+    pub message_rate: f64,
+    /// How many messages a user may send at once before being limited.
+    pub message_burst: u32,
+    /// How many times a flooding user is warned before being muted.
+    pub warnings: u32,
+    /// How many seconds a flooding user is muted for. Users who keep flooding while
+    /// muted are disconnected.
+    pub mute_seconds: u64,
+    /// How many connections a single IP address may have open at once.
+    pub max_connections_per_ip: usize,
+    /// How many of an IP address's connections may be in the handshake at once.
+    pub max_handshakes_per_ip: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            max_users: 50,
+            log_level: LevelFilter::Info,
+            // This is kept well below the clients' limit, since relayed messages
+            // grow by the sender's nick.
+            max_message_size: 16 * 1024,
+            outbound_queue_size: 256,
+            heartbeat_interval: 30,
+            idle_timeout: 90,
+            handshake_timeout: 10,
+            shutdown_timeout: 10,
+            shutdown_message: String::new(),
+            operators: Vec::new(),
+            operator_accounts: Vec::new(),
+            bans_path: PathBuf::from("bans.json"),
+            encryption: EncryptionConfig::default(),
+            history: HistoryConfig::default(),
+            accounts: AccountsConfig::default(),
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        let rekey = RekeyPolicy::default();
+        EncryptionConfig {
+            enabled: true,
+            identity_key: PathBuf::from("identity.key"),
+            rekey_after_frames: rekey.max_frames,
+            rekey_after_bytes: rekey.max_bytes,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: true,
+            path: PathBuf::from("history.log"),
+            replay: 50,
+            max_messages: 1000,
+            max_age_days: 30,
+        }
+    }
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            enabled: true,
+            path: PathBuf::from("accounts.json"),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            message_rate: 2.0,
+            message_burst: 10,
+            warnings: 3,
+            mute_seconds: 60,
+            max_connections_per_ip: 10,
+            max_handshakes_per_ip: 3,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the config file given on the command line (if
+    /// any) and the command line options, and validates it.
+    pub fn from_cli(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::load(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(bind) = cli.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(max_users) = cli.max_users {
+            config.max_users = max_users;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.into()));
+
+        if self.port == 0 {
+            return invalid("port must not be 0");
+        }
+        if self.max_users == 0 {
+            return invalid("max_users must be at least 1");
+        }
+        if self.max_message_size < MSG_LENGTH {
+            return invalid(&format!(
+                "max_message_size must be at least {} bytes",
+                MSG_LENGTH
+            ));
+        }
+        if self.outbound_queue_size == 0 {
+            return invalid("outbound_queue_size must be at least 1");
+        }
+        if self.heartbeat_interval == 0 || self.handshake_timeout == 0 {
+            return invalid("heartbeat_interval and handshake_timeout must not be 0");
+        }
+        let timeouts = [
+            self.heartbeat_interval,
+            self.idle_timeout,
+            self.handshake_timeout,
+            self.shutdown_timeout,
+        ];
+        if timeouts.into_iter().any(|seconds| seconds > MAX_SECONDS) {
+            return invalid(&format!(
+                "heartbeat_interval, idle_timeout, handshake_timeout and shutdown_timeout \
+                 must not be more than {} seconds",
+                MAX_SECONDS
+            ));
+        }
+        if self.idle_timeout <= self.heartbeat_interval {
+            // quiet connections need time to answer the ping
+            return invalid("idle_timeout must be longer than heartbeat_interval");
+        }
+        if let Some(e) = self
+            .operator_accounts
+            .iter()
+            .find_map(|nick| Nick::new(nick).err())
+        {
+            return invalid(&format!("operator_accounts: {}", e));
+        }
+        if self.bans_path.as_os_str().is_empty() {
+            return invalid("bans_path must not be empty");
+        }
+        if self.encryption.enabled && self.encryption.identity_key.as_os_str().is_empty() {
+            return invalid("encryption.identity_key must not be empty");
+        }
+        if self.encryption.rekey_after_frames == 0 || self.encryption.rekey_after_bytes == 0 {
+            return invalid("encryption.rekey_after_frames and rekey_after_bytes must not be 0");
+        }
+        if self.history.enabled && self.history.path.as_os_str().is_empty() {
+            return invalid("history.path must not be empty");
+        }
+        if self.history.replay > self.history.max_messages {
+            return invalid("history.replay must not be more than history.max_messages");
+        }
+        if self.history.enabled && self.outbound_queue_size <= self.history.replay {
+            // the whole replay is queued at once when a user joins a room
+            return invalid("outbound_queue_size must be larger than history.replay");
+        }
+        if self.history.max_age_days < 0 {
+            return invalid("history.max_age_days must not be negative");
+        }
+        if self.history.max_age_days > MAX_AGE_DAYS {
+            return invalid(&format!(
+                "history.max_age_days must not be more than {}",
+                MAX_AGE_DAYS
+            ));
+        }
+        if self.accounts.enabled && self.accounts.path.as_os_str().is_empty() {
+            return invalid("accounts.path must not be empty");
+        }
+        let rate = self.limits.message_rate;
+        if !rate.is_finite() || rate <= 0.0 || self.limits.message_burst == 0 {
+            return invalid("limits.message_rate and message_burst must be more than 0");
+        }
+        if self.limits.mute_seconds == 0 {
+            return invalid("limits.mute_seconds must not be 0");
+        }
+        if self.limits.mute_seconds > MAX_SECONDS {
+            return invalid(&format!(
+                "limits.mute_seconds must not be more than {} seconds",
+                MAX_SECONDS
+            ));
+        }
+        if self.limits.max_connections_per_ip == 0 || self.limits.max_handshakes_per_ip == 0 {
+            return invalid(
+                "limits.max_connections_per_ip and max_handshakes_per_ip must not be 0",
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns whether a user logged in to `account` is an operator.
+    pub fn is_operator_account(&self, account: &Nick) -> bool {
+        self.operator_accounts
+            .iter()
+            .any(|operator| account.eq_ignore_ascii_case(operator))
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+
+    pub fn rekey_policy(&self) -> RekeyPolicy {
+        RekeyPolicy {
+            max_frames: self.encryption.rekey_after_frames,
+            max_bytes: self.encryption.rekey_after_bytes,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! The chat history, kept in memory for replaying to users when they join a room,
+//! and in an append-only log file so that it survives restarts.
+//!
+//! Each line of the log is a JSON object with the message's ID, time, room, nick and
+//! text. The log is rewritten without the expired messages at startup, and whenever it
+//! grows to twice the size of what is retained. Rewritten logs start with a line holding
+//! the highest ID used so far, so that IDs aren't reused when every message expired.
+//!
+//! After startup, the log is written by a blocking task of its own, so that recording
+//! a message never makes the router wait for the disk.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Duration, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use chat_rs::{Msg, Nick, Room, Stamp};
+
+use crate::config::HistoryConfig;
+use crate::files;
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Entry(Entry),
+    LastId { last_id: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    id: u64,
+    time: DateTime<Utc>,
+    room: String,
+    nick: String,
+    text: String,
+}
+
+/// A write to the log file, carried out by `write_log`.
+enum LogWrite {
+    Append(Entry),
+    /// Replaces the log with the given entries, which are sorted by ID.
+    Rewrite {
+        last_id: u64,
+        entries: Vec<Entry>,
+    },
+}
+
+pub struct History {
+    /// How many messages are replayed to a user joining a room.
+    replay: usize,
+    /// How many messages are kept per room.
+    max_messages: usize,
+    max_age: Option<Duration>,
+    rooms: HashMap<Room, VecDeque<Entry>>,
+    /// The queue of the task writing the log file.
+    log: UnboundedSender<LogWrite>,
+    /// The number of entries in the log file, expired ones included.
+    logged: usize,
+    /// The highest message ID ever logged. Expired messages count towards it too, so
+    /// that IDs are never reused.
+    last_id: u64,
+}
+
+impl History {
+    /// Loads the history from the log file at `config.path`, creating it if it doesn't
+    /// exist. Lines that can't be parsed are skipped. This starts the task writing the
+    /// log, so it has to be called from within the Tokio runtime.
+    pub fn open(config: &HistoryConfig) -> io::Result<Self> {
+        let (log, writes) = mpsc::unbounded_channel();
+        let mut history = History {
+            replay: config.replay,
+            max_messages: config.max_messages,
+            max_age: (config.max_age_days > 0).then(|| Duration::days(config.max_age_days)),
+            rooms: HashMap::new(),
+            log,
+            logged: 0,
+            last_id: 0,
+        };
+
+        // creates the log if it doesn't exist yet
+        open_log(&config.path)?;
+        let reader = BufReader::new(File::open(&config.path)?);
+        for (number, line) in reader.lines().enumerate() {
+            let entry = match serde_json::from_str(&line?) {
+                Ok(Line::Entry(entry)) => entry,
+                Ok(Line::LastId { last_id }) => {
+                    history.last_id = history.last_id.max(last_id);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping unreadable history on line {}: {}", number + 1, e);
+                    continue;
+                }
+            };
+            history.last_id = history.last_id.max(entry.id);
+            match (
+                Room::new(entry.room.as_str()),
+                Nick::new(entry.nick.as_str()),
+            ) {
+                (Ok(room), Ok(_)) => history.rooms.entry(room).or_default().push_back(entry),
+                _ => warn!("Skipping invalid history on line {}", number + 1),
+            }
+        }
+        history.expire();
+        let entries = history.sorted_entries();
+        history.logged = entries.len();
+        let file = rewrite_log(&config.path, history.last_id, &entries)?;
+
+        let path = config.path.clone();
+        // this ends once the history, and with it the queue, is dropped
+        tokio::task::spawn_blocking(move || write_log(path, file, writes));
+        Ok(history)
+    }
+
+    /// Returns the highest message ID in the history, or 0 if it's empty.
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    /// Records a chat message sent to `room`. It's written to the log in the
+    /// background, where errors are logged.
+    pub fn record(&mut self, room: &Room, stamp: &Stamp, nick: &Nick, text: &str) {
+        let entry = Entry {
+            id: stamp.id,
+            time: DateTime::from(stamp.time),
+            room: room.to_string(),
+            nick: nick.to_string(),
+            text: text.to_string(),
+        };
+        self.write(LogWrite::Append(entry.clone()));
+        self.logged += 1;
+        self.last_id = self.last_id.max(stamp.id);
+
+        self.rooms.entry(room.clone()).or_default().push_back(entry);
+        self.expire();
+        if self.logged > 2 * self.retained().max(self.max_messages) {
+            let entries = self.sorted_entries();
+            self.logged = entries.len();
+            self.write(LogWrite::Rewrite {
+                last_id: self.last_id,
+                entries,
+            });
+        }
+    }
+
+    fn write(&self, write: LogWrite) {
+        // the writer only stops once the history is dropped
+        self.log.send(write).unwrap_or(());
+    }
+
+    /// Returns the latest messages of `room`, oldest first, as `StampedHistoryMsg`s.
+    pub fn replay(&mut self, room: &Room) -> Vec<Msg> {
+        self.expire();
+        let entries = match self.rooms.get(room) {
+            Some(entries) => entries,
+            None => return Vec::new(),
+        };
+        entries
+            .iter()
+            .skip(entries.len().saturating_sub(self.replay))
+            .filter_map(|entry| {
+                // always valid, since entries are checked on load
+                let nick = Nick::new(entry.nick.as_str()).ok()?;
+                let stamp = Stamp {
+                    id: entry.id,
+                    time: SystemTime::from(entry.time),
+                };
+                Some(Msg::StampedHistoryMsg(stamp, nick, entry.text.clone()))
+            })
+            .collect()
+    }
+
+    /// Drops the messages that are past the retention limits.
+    fn expire(&mut self) {
+        let cutoff = self.max_age.map(|max_age| Utc::now() - max_age);
+        for entries in self.rooms.values_mut() {
+            while entries.len() > self.max_messages {
+                entries.pop_front();
+            }
+            if let Some(cutoff) = cutoff {
+                while entries.front().is_some_and(|entry| entry.time < cutoff) {
+                    entries.pop_front();
+                }
+            }
+        }
+        self.rooms.retain(|_, entries| !entries.is_empty());
+    }
+
+    fn retained(&self) -> usize {
+        self.rooms.values().map(VecDeque::len).sum()
+    }
+
+    /// Returns the retained messages, sorted by ID, for rewriting the log.
+    fn sorted_entries(&self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = self.rooms.values().flatten().cloned().collect();
+        entries.sort_by_key(|entry| entry.id);
+        entries
+    }
+}
+
+/// Carries out the writes to the log file at `path`, in order, until the history is
+/// dropped. This blocks on the disk, and runs on a thread of its own.
+fn write_log(path: PathBuf, mut file: File, mut writes: UnboundedReceiver<LogWrite>) {
+    while let Some(write) = writes.blocking_recv() {
+        let result = match write {
+            LogWrite::Append(entry) => append_log(&mut file, &entry),
+            LogWrite::Rewrite { last_id, entries } => {
+                rewrite_log(&path, last_id, &entries).map(|rewritten| file = rewritten)
+            }
+        };
+        if let Err(e) = result {
+            error!("Error writing the history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn append_log(file: &mut File, entry: &Entry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+/// Replaces the log file with the given entries, and returns it opened for appending.
+fn rewrite_log(path: &Path, last_id: u64, entries: &[Entry]) -> io::Result<File> {
+    files::replace(path, |writer| {
+        writeln!(writer, "{{\"last_id\":{}}}", last_id)?;
+        for entry in entries {
+            serde_json::to_writer(&mut *writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    })?;
+
+    open_log(path)
+}
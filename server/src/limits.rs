@@ -0,0 +1,192 @@
+//! Flood protection: rate limits for the messages of each connection, and caps on the
+//! connections of each IP address.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::LimitsConfig;
+
+/// A token bucket, which holds up to `capacity` tokens and refills at `rate` tokens
+/// per second.
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, rate: f64) -> Self {
+        TokenBucket {
+            capacity: capacity.into(),
+            rate,
+            tokens: capacity.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes a token, if there is one.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// What to do with a message from a connection.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Drop the message, without telling the user.
+    Drop,
+    /// Drop the message, and warn the user that they're sending too fast.
+    Warn,
+    /// Drop the message, and mute the user for the given time.
+    Mute(Duration),
+    /// The user kept flooding while muted, and should be disconnected.
+    Disconnect,
+}
+
+/// Limits how fast a single connection may send messages. Users who go over the limit
+/// are warned a few times, then muted, and disconnected if they keep going. Every
+/// burst of messages over the limit counts once, however long it is.
+pub struct FloodGuard {
+    bucket: TokenBucket,
+    warnings: u32,
+    mute: Duration,
+    /// Whether the user's last message was over the limit.
+    limited: bool,
+    /// How many times the user has been warned since they were last muted.
+    warned: u32,
+    last_warned: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(config: &LimitsConfig) -> Self {
+        FloodGuard {
+            bucket: TokenBucket::new(config.message_burst, config.message_rate),
+            warnings: config.warnings,
+            mute: Duration::from_secs(config.mute_seconds),
+            limited: false,
+            warned: 0,
+            last_warned: None,
+            muted_until: None,
+        }
+    }
+
+    /// Decides what to do with a message the user just sent.
+    pub fn check(&mut self) -> Verdict {
+        let now = Instant::now();
+        let allowed = self.bucket.take();
+        let muted = self.muted_until.is_some_and(|until| now < until);
+
+        if allowed {
+            self.limited = false;
+            return if muted { Verdict::Drop } else { Verdict::Allow };
+        }
+        if self.limited {
+            return Verdict::Drop;
+        }
+        self.limited = true;
+        if muted {
+            return Verdict::Disconnect;
+        }
+        // warnings are forgotten once the user has behaved for as long as a mute lasts
+        if self
+            .last_warned
+            .is_some_and(|warned| now - warned >= self.mute)
+        {
+            self.warned = 0;
+        }
+        if self.warned < self.warnings {
+            self.warned += 1;
+            self.last_warned = Some(now);
+            return Verdict::Warn;
+        }
+        self.warned = 0;
+        self.last_warned = None;
+        self.muted_until = Some(now + self.mute);
+        Verdict::Mute(self.mute)
+    }
+}
+
+/// Counts the open connections and handshakes of every IP address.
+pub struct IpLimits {
+    max_connections: usize,
+    max_handshakes: usize,
+    /// The open connections and handshakes, by address.
+    counts: Mutex<HashMap<IpAddr, (usize, usize)>>,
+}
+
+/// A connection or handshake counted against an address, until it's dropped.
+pub struct IpSlot {
+    limits: Arc<IpLimits>,
+    address: IpAddr,
+    handshake: bool,
+}
+
+impl IpLimits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        IpLimits {
+            max_connections: config.max_connections_per_ip,
+            max_handshakes: config.max_handshakes_per_ip,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a new connection from `address`, unless it already has too many.
+    pub fn connect(self: &Arc<Self>, address: IpAddr) -> Option<IpSlot> {
+        self.acquire(address, false)
+    }
+
+    /// Counts a handshake from `address`, unless it already has too many in progress.
+    pub fn handshake(self: &Arc<Self>, address: IpAddr) -> Option<IpSlot> {
+        self.acquire(address, true)
+    }
+
+    fn acquire(self: &Arc<Self>, address: IpAddr, handshake: bool) -> Option<IpSlot> {
+        let mut counts = self.counts.lock().unwrap();
+        let (connections, handshakes) = counts.entry(address).or_default();
+        let (count, max) = if handshake {
+            (handshakes, self.max_handshakes)
+        } else {
+            (connections, self.max_connections)
+        };
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            limits: self.clone(),
+            address,
+            handshake,
+        })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        if let Some((connections, handshakes)) = counts.get_mut(&self.address) {
+            if self.handshake {
+                *handshakes -= 1;
+            } else {
+                *connections -= 1;
+            }
+            if (*connections, *handshakes) == (0, 0) {
+                counts.remove(&self.address);
+            }
+        }
+    }
+}
@@ -1,206 +1,1112 @@
 use std::collections::HashMap;
-use std::env;
 use std::io;
+use std::net::IpAddr;
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use log::{debug, error, info, trace, warn, LevelFilter};
+use clap::Parser;
+use futures::{FutureExt, SinkExt, StreamExt};
+use log::{debug, error, info, trace, warn};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::{oneshot, Mutex};
+use tokio::time::{interval_at, timeout, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
 use chat_rs::*;
 
-const MAX_USERS: usize = 50;
-type UsersType = Arc<Mutex<HashMap<String, ChatWriterHalf>>>;
+mod accounts;
+mod commands;
+mod config;
+mod files;
+mod history;
+mod limits;
+mod moderation;
+use accounts::Accounts;
+use commands::{Action, Caller};
+use config::{Cli, Config};
+use history::History;
+use limits::{FloodGuard, IpLimits, Verdict};
+use moderation::{Ban, BanTarget, Moderation};
+
+/// A connected user's outbound queue, and the room they're in.
+struct User {
+    address: IpAddr,
+    /// The queue of messages for the connection's writer task.
+    outbox: Sender<Msg>,
+    /// Tells the writer task to drop the queue and close the connection, with the
+    /// message that says why.
+    kick: Option<oneshot::Sender<Msg>>,
+    room: Room,
+    /// Whether the user's client supports `Capability::Timestamps`.
+    stamps: bool,
+    /// Whether the user may run operator-only commands.
+    operator: bool,
+}
+
+impl User {
+    /// Queues a message for the user. If their queue is full, they aren't keeping up
+    /// and are disconnected instead.
+    fn send(&mut self, msg: Msg) {
+        match self.outbox.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.disconnect("too many unread messages".into()),
+            // the connection is already closing
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    fn disconnect(&mut self, reason: String) {
+        self.close(Msg::ConnectionClosed(reason));
+    }
+
+    /// Disconnects the user for good, so that their client doesn't reconnect.
+    fn remove(&mut self, reason: String) {
+        self.close(Msg::Kicked(reason));
+    }
+
+    fn close(&mut self, msg: Msg) {
+        if let Some(kick) = self.kick.take() {
+            kick.send(msg).unwrap_or(());
+        }
+    }
+}
+
+/// How long a kicked user's connection is kept open to tell them why.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type UsersType = Arc<Mutex<HashMap<Nick, User>>>;
+type HistoryType = Option<Arc<std::sync::Mutex<History>>>;
+type AccountsType = Option<Arc<std::sync::Mutex<Accounts>>>;
+type ModerationType = Arc<std::sync::Mutex<Moderation>>;
+
+/// Everything a connection shares with the rest of the server.
+#[derive(Clone)]
+struct Shared {
+    users: UsersType,
+    tx: Sender<(Msg, Target)>,
+    config: Arc<Config>,
+    identity: Option<Arc<Identity>>,
+    history: HistoryType,
+    accounts: AccountsType,
+    moderation: ModerationType,
+    ip_limits: Arc<IpLimits>,
+    /// Cancelled when the server shuts down.
+    shutdown: CancellationToken,
+}
+
+/// How a user logged in.
+#[derive(Debug, PartialEq)]
+enum Login {
+    /// Without a password.
+    Guest,
+    /// With the password of their nick's account.
+    Account,
+    /// With a password, which was ignored since their nick isn't registered.
+    Unregistered,
+}
+
+/// Who a routed message is delivered to.
+#[derive(Debug)]
+enum Target {
+    Everyone,
+    Room(Room),
+    User(Nick),
+}
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let running = Arc::new(AtomicBool::new(true));
-    env_logger::Builder::new()
-        .filter_level(LevelFilter::Info)
-        .parse_default_env()
-        .init();
-
-    let address = env::args().nth(1).unwrap_or_else(|| {
-        warn!("Bind IP missing, assuming 0.0.0.0");
-        "0.0.0.0".into()
+    let cli = Cli::parse();
+    let config = Config::from_cli(&cli).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
     });
 
-    let is_encrypted = env::var("CHAT_RS_UNENCRYPTED").is_err();
-    if is_encrypted {
-        info!("This server only accepts encrypted connections.")
-    } else {
-        info!("This server is operating in unencrypted mode.")
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(config.log_level).parse_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
     }
+    logger.init();
 
-    info!("Listening to connections on {}:7878", address);
-    let listener = TcpListener::bind(format!("{}:7878", address))
-        .await
-        .unwrap_or_else(|err| {
-            error!("Error on binding listener: {}", err.to_string());
+    let identity = if config.encryption.enabled {
+        let path = &config.encryption.identity_key;
+        let identity = Identity::load_or_generate(path).unwrap_or_else(|err| {
+            error!(
+                "Error loading server identity from {}: {}",
+                path.display(),
+                err
+            );
             process::exit(1);
         });
+        info!("This server only accepts encrypted connections.");
+        info!("Server identity fingerprint: {}", identity.fingerprint());
+        Some(Arc::new(identity))
+    } else {
+        info!("This server is operating in unencrypted mode.");
+        None
+    };
 
-    let users: UsersType = Arc::from(Mutex::from(HashMap::with_capacity(MAX_USERS)));
-
-    let uclone: UsersType = users.clone();
-    let rclone = running.clone();
-    ctrlc::set_handler(move || {
-        rclone.store(false, Ordering::SeqCst);
-        info!("Received CTRL+C, exiting...");
-
-        let uclone = uclone.clone();
-        debug!("Acquired users lock");
-        tokio::runtime::Runtime::new()
-            .unwrap()
-            .block_on(async move {
-                let mut users = uclone.lock().await;
-                for (nick, writer) in users.iter_mut() {
-                    debug!("Shutting down {}'s stream", nick);
-                    let (mut inner, _) = writer.get_writer_cipher();
-
-                    tokio::io::AsyncWriteExt::shutdown(&mut inner)
-                        .await
-                        .unwrap_or(());
-                }
-                process::exit(0);
-            });
-    })
-    .unwrap();
+    let history = if config.history.enabled {
+        let history = History::open(&config.history).unwrap_or_else(|err| {
+            error!(
+                "Error loading the history from {}: {}",
+                config.history.path.display(),
+                err
+            );
+            process::exit(1);
+        });
+        Some(Arc::new(std::sync::Mutex::new(history)))
+    } else {
+        None
+    };
+
+    let accounts = if !config.accounts.enabled {
+        None
+    } else if identity.is_none() {
+        warn!("Accounts are disabled, since passwords would be sent unencrypted.");
+        None
+    } else {
+        let accounts = Accounts::open(&config.accounts.path).unwrap_or_else(|err| {
+            error!(
+                "Error loading the accounts from {}: {}",
+                config.accounts.path.display(),
+                err
+            );
+            process::exit(1);
+        });
+        info!("Loaded {} registered accounts.", accounts.count());
+        Some(Arc::new(std::sync::Mutex::new(accounts)))
+    };
+
+    let moderation = Moderation::open(&config.bans_path).unwrap_or_else(|err| {
+        error!(
+            "Error loading the bans from {}: {}",
+            config.bans_path.display(),
+            err
+        );
+        process::exit(1);
+    });
+    info!("Loaded {} bans.", moderation.bans().count());
+    let moderation = Arc::new(std::sync::Mutex::new(moderation));
+
+    let address = std::net::SocketAddr::new(config.bind, config.port);
+    info!("Listening to connections on {}", address);
+    let listener = TcpListener::bind(address).await.unwrap_or_else(|err| {
+        error!("Error on binding listener: {}", err);
+        process::exit(1);
+    });
+
+    let users: UsersType = Arc::from(Mutex::from(HashMap::with_capacity(config.max_users)));
 
     let (tx, rx) = mpsc::channel(32);
     let uclone = users.clone();
+    let hclone = history.clone();
 
     tokio::spawn(async move {
-        route_messages(rx, users).await;
+        route_messages(rx, users, hclone).await;
     });
-    accept_connections(listener, uclone, running.clone(), tx, is_encrypted).await;
+    let config = Arc::new(config);
+    let shutdown = CancellationToken::new();
+    let shared = Shared {
+        users: uclone,
+        tx,
+        ip_limits: Arc::new(IpLimits::new(&config.limits)),
+        config: config.clone(),
+        identity,
+        history,
+        accounts,
+        moderation,
+        shutdown: shutdown.clone(),
+    };
+    let connections = TaskTracker::new();
+    tokio::select! {
+        _ = accept_connections(listener, shared, &connections) => {}
+        _ = shutdown_signal() => {}
+    }
 
-    loop {
-        std::thread::yield_now()
-    } // ensures that main waits for ctrlc handler to finish
+    // every connection tells its user and drains its own queue, and whatever is
+    // still running after the deadline is dropped along with the runtime
+    info!("Shutting down...");
+    shutdown.cancel();
+    connections.close();
+    tokio::select! {
+        drained = timeout(config.shutdown_timeout(), connections.wait()) => match drained {
+            Ok(()) => info!("All connections closed."),
+            Err(_) => warn!("{} connections didn't close in time.", connections.len()),
+        },
+        _ = shutdown_signal() => warn!("Exiting without waiting for the connections to close."),
+    }
+    Ok(())
 }
 
-async fn route_messages(mut rx: Receiver<(Msg, Option<String>)>, users: UsersType) {
-    loop {
-        let (msg, recepient) = rx.recv().await.unwrap();
-        if recepient.is_none() {
-            // message is to be broadcasted
-            let mut users = users.lock().await;
-            for stream in users.values_mut() {
-                stream.send_msg(&msg).await.unwrap_or(()); // ignore failed sends
+/// Waits for CTRL+C, or on Unix for SIGTERM, which is what service managers and
+/// container runtimes stop processes with.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Error listening for CTRL+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Error listening for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received CTRL+C."),
+        _ = terminate => info!("Received SIGTERM."),
     }
 }
 
-async fn accept_connections(
-    listener: TcpListener,
-    users: UsersType,
-    running: Arc<AtomicBool>,
-    tx: Sender<(Msg, Option<String>)>,
-    is_encrypted: bool,
+/// Delivers messages to their targets. This is also where chat messages are stamped
+/// and recorded, so that their IDs increase in the order they're delivered in.
+async fn route_messages(mut rx: Receiver<(Msg, Target)>, users: UsersType, history: HistoryType) {
+    let mut next_id = match &history {
+        Some(history) => history.lock().unwrap().last_id() + 1,
+        None => 1,
+    };
+
+    // this ends once every connection, and the accept loop, are gone
+    while let Some((mut msg, target)) = rx.recv().await {
+        if let (Msg::NickedUserMsg(nick, text), Target::Room(room)) = (&msg, &target) {
+            let stamp = Stamp {
+                id: next_id,
+                time: SystemTime::now(),
+            };
+            next_id += 1;
+            if let Some(history) = &history {
+                history.lock().unwrap().record(room, &stamp, nick, text);
+            }
+            msg = Msg::StampedUserMsg(stamp, nick.clone(), text.clone());
+        }
+        let unstamped = msg.clone().without_stamp();
+
+        // this only queues the messages, so a slow user can't hold up everyone else
+        let mut users = users.lock().await;
+        let recipients: Vec<&mut User> = match &target {
+            Target::Everyone => users.values_mut().collect(),
+            Target::Room(room) => users
+                .values_mut()
+                .filter(|user| user.room == *room)
+                .collect(),
+            Target::User(nick) => users.get_mut(nick).into_iter().collect(),
+        };
+        for user in recipients {
+            let msg = if user.stamps { &msg } else { &unstamped };
+            user.send(msg.clone());
+        }
+    }
+}
+
+/// Sends a connection's queued messages until the queue is closed, or until the user
+/// is kicked, in which case the rest of the queue is dropped and the message saying why
+/// is sent instead. A user that isn't reading at all is only waited on for `CLOSE_TIMEOUT`.
+async fn write_messages(
+    writer: ChatWriterHalf,
+    mut outbox: Receiver<Msg>,
+    kick: oneshot::Receiver<Msg>,
 ) {
-    loop {
-        if !running.load(Ordering::SeqCst) {
-            break;
+    let mut writer = writer.into_framed();
+    let mut kick = kick.fuse();
+    let closed = loop {
+        let msg = tokio::select! {
+            biased;
+            Ok(closed) = &mut kick => break Some(closed),
+            msg = outbox.recv() => match msg {
+                Some(msg) => msg,
+                None => break None,
+            },
+        };
+
+        let send = writer.send(msg);
+        tokio::pin!(send);
+        tokio::select! {
+            biased;
+            Ok(closed) = &mut kick => {
+                // the message may be half written, and has to be finished first
+                match timeout(CLOSE_TIMEOUT, send).await {
+                    Ok(Ok(())) => break Some(closed),
+                    _ => return,
+                }
+            }
+            result = &mut send => {
+                if result.is_err() {
+                    return;
+                }
+            }
         }
+    };
+
+    if let Some(closed) = closed {
+        timeout(CLOSE_TIMEOUT, writer.send(closed)).await.ok();
+    }
+    timeout(CLOSE_TIMEOUT, writer.close()).await.ok();
+}
+
+/// Accepts connections until the returned future is dropped. The connections are
+/// spawned on `connections`, so that they can be waited for on shutdown.
+async fn accept_connections(listener: TcpListener, shared: Shared, connections: &TaskTracker) {
+    loop {
         if let Ok((stream, _)) = listener.accept().await {
-            let uclone = users.clone();
-            let tx = tx.clone();
-            tokio::spawn(async move {
-                handle_connection(ChatStream::new(stream), uclone, tx, is_encrypted).await;
+            let shared = shared.clone();
+            connections.spawn(async move {
+                handle_connection(ChatStream::new(stream), shared).await;
             });
         }
     }
 }
 
-async fn handle_connection(
-    mut stream: ChatStream,
-    users: UsersType,
-    tx: Sender<(Msg, Option<String>)>,
-    is_encrypted: bool,
-) {
+async fn handle_connection(mut stream: ChatStream, shared: Shared) {
+    let Shared {
+        users,
+        tx,
+        config,
+        identity,
+        history,
+        accounts,
+        moderation,
+        ip_limits,
+        shutdown,
+    } = shared;
     let peer_address = stream.peer_addr().unwrap();
     debug!("Incoming connection from {}", peer_address);
 
+    let ban = moderation
+        .lock()
+        .unwrap()
+        .address_ban(peer_address.ip())
+        .cloned();
+    if let Some(ban) = ban {
+        return reject(stream, &ban.explain()).await;
+    }
+
+    // the connection is counted against its address until it's closed
+    let _connection = match ip_limits.connect(peer_address.ip()) {
+        Some(slot) => slot,
+        None => return reject(stream, "too many connections from your address").await,
+    };
+    let handshake_slot = match ip_limits.handshake(peer_address.ip()) {
+        Some(slot) => slot,
+        None => return reject(stream, "too many handshakes from your address").await,
+    };
+
+    stream.set_max_message_size(config.max_message_size);
+    stream.set_rekey_policy(config.rekey_policy());
+
+    let login = log_in(
+        &mut stream,
+        &users,
+        &config,
+        &identity,
+        &accounts,
+        &moderation,
+    );
+    let login = tokio::select! {
+        login = timeout(config.handshake_timeout(), login) => login,
+        _ = shutdown.cancelled() => return,
+    };
+    let (mut nick, handshake, login) = match login {
+        Ok(Some(login)) => login,
+        Ok(None) => return,
+        Err(_) => {
+            info!("{} timed out during the handshake", peer_address);
+            return;
+        }
+    };
+    drop(handshake_slot);
+
+    info!("Connection successful from {}, nick {}", peer_address, nick);
+    let mut room = Room::lobby();
+    let (reader, writer) = stream.into_split();
+    let (outbox, outbox_rx) = mpsc::channel(config.outbound_queue_size);
+    let (kick, kick_rx) = oneshot::channel();
+    let mut writer_task = tokio::spawn(write_messages(writer, outbox_rx, kick_rx));
+    let user = User {
+        address: peer_address.ip(),
+        outbox: outbox.clone(),
+        kick: Some(kick),
+        room: room.clone(),
+        stamps: handshake.supports(Capability::Timestamps),
+        // an address alone could be shared by anyone behind the same NAT or proxy
+        operator: login == Login::Account
+            && (config.operators.contains(&peer_address.ip()) || config.is_operator_account(&nick)),
+    };
+    {
+        let mut users = users.lock().await;
+        users.insert(nick.clone(), user);
+        send_roster(&mut users, &nick, &room);
+    }
+    // the account the user is logged in to, if any
+    let mut account = (login == Login::Account).then(|| nick.clone());
+    if login == Login::Unregistered {
+        let notice = format!(
+            "{} isn't registered, so you are connected as a guest. Use /register to register it.",
+            nick
+        );
+        tx.send((Msg::ServerReply(notice), Target::User(nick.clone())))
+            .await
+            .unwrap();
+    }
+    replay_history(&history, &room, &nick, &tx).await;
+    tx.send((Msg::NickedConnect(nick.clone()), Target::Room(room.clone())))
+        .await
+        .unwrap();
+
+    let mut messages = reader.into_framed();
+    // clients from before keepalives neither answer pings nor send anything while idle
+    let keepalive = handshake.version >= Msg::Ping.version();
+    let mut heartbeat = interval_at(
+        Instant::now() + config.heartbeat_interval(),
+        config.heartbeat_interval(),
+    );
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_heard = Instant::now();
+    let mut flood = FloodGuard::new(&config.limits);
+    let mut shutting_down = false;
+    'receive: loop {
+        let msg = tokio::select! {
+            msg = messages.next() => msg,
+            _ = heartbeat.tick(), if keepalive => {
+                let quiet = last_heard.elapsed();
+                if quiet >= config.idle_timeout() {
+                    info!("{} [{}] timed out.", peer_address, nick);
+                    if let Some(user) = users.lock().await.get_mut(&nick) {
+                        user.disconnect("timed out".into());
+                    }
+                    break;
+                }
+                if quiet >= config.heartbeat_interval() {
+                    // a full queue gets the user kicked anyway
+                    outbox.try_send(Msg::Ping).ok();
+                }
+                continue;
+            }
+            // the connection was kicked, or writing to it failed
+            _ = &mut writer_task => break,
+            _ = shutdown.cancelled() => {
+                shutting_down = true;
+                break;
+            }
+        };
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            None => break,
+            Some(Err(ChatError::Closed)) => break,
+            Some(Err(e)) if e.is_tampering() => {
+                warn!("Possible tampering on {} [{}]: {}", peer_address, nick, e);
+                break;
+            }
+            Some(Err(e)) => {
+                debug!("Associated error: {}", e);
+                break;
+            }
+        };
+
+        last_heard = Instant::now();
+        match &msg {
+            // keep passwords out of the log
+            Msg::Register(_) => trace!("Msg({}): [{}]: <password>", msg.code(), nick),
+            Msg::Command(s) if s.trim_start().starts_with("register") => {
+                trace!("Msg({}): [{}]: register <password>", msg.code(), nick)
+            }
+            _ => trace!("Msg({}): [{}]: {}", msg.code(), nick, msg.string()),
+        }
+        // operator status can be granted and taken away by other operators
+        let is_operator = users
+            .lock()
+            .await
+            .get(&nick)
+            .is_some_and(|user| user.operator);
+        // keepalives and operators don't count against the rate limit
+        if !is_operator && !matches!(msg, Msg::Ping | Msg::Pong) {
+            // a full queue gets the user kicked anyway, so notices are only tried
+            match flood.check() {
+                Verdict::Allow => {}
+                Verdict::Drop => continue,
+                Verdict::Warn => {
+                    let notice = "You are sending messages too fast, slow down.";
+                    outbox.try_send(Msg::Notice(notice.into())).ok();
+                    continue;
+                }
+                Verdict::Mute(duration) => {
+                    info!("{} [{}] was muted for flooding.", peer_address, nick);
+                    let notice = format!(
+                        "You are muted for {}s for flooding. Keep it up and you'll be disconnected.",
+                        duration.as_secs()
+                    );
+                    outbox.try_send(Msg::Notice(notice)).ok();
+                    continue;
+                }
+                Verdict::Disconnect => {
+                    info!("{} [{}] was disconnected for flooding.", peer_address, nick);
+                    if let Some(user) = users.lock().await.get_mut(&nick) {
+                        user.disconnect("flooding".into());
+                    }
+                    break;
+                }
+            }
+        }
+        let actions = match msg {
+            Msg::UserMsg(s) => vec![Action::Broadcast(Msg::NickedUserMsg(nick.clone(), s))],
+            Msg::NickChange(s) => vec![Action::ChangeNick(s)],
+            Msg::Register(password) => vec![Action::Register(password)],
+            Msg::Ping => {
+                outbox.try_send(Msg::Pong).ok();
+                continue;
+            }
+            Msg::Pong => continue,
+            msg => {
+                let online = online_users(&users).await;
+                let caller = Caller {
+                    nick: &nick,
+                    room: &room,
+                    is_operator,
+                    online: &online,
+                };
+                match msg {
+                    Msg::PrivateMsg(to, text) => vec![commands::private_msg(&caller, &to, &text)],
+                    Msg::Command(s) => commands::dispatch(&caller, &s),
+                    Msg::JoinRoom(new) => commands::join_room(&caller, new),
+                    Msg::PartRoom => commands::part_room(&caller),
+                    Msg::ListRooms => commands::list_rooms(&caller),
+                    _ => vec![],
+                }
+            }
+        };
+
+        for action in actions {
+            match action {
+                Action::Reply(s) => {
+                    tx.send((Msg::ServerReply(s), Target::User(nick.clone())))
+                        .await
+                }
+                Action::Error(s) => tx.send((Msg::Error(s), Target::User(nick.clone()))).await,
+                Action::Broadcast(_) | Action::SendTo(_, _)
+                    if !is_operator && moderation.lock().unwrap().is_muted(&nick) =>
+                {
+                    let error = Msg::Error("you are muted".into());
+                    tx.send((error, Target::User(nick.clone()))).await
+                }
+                Action::Broadcast(msg) => tx.send((msg, Target::Room(room.clone()))).await,
+                Action::SendTo(to, msg) => tx.send((msg, Target::User(to))).await,
+                Action::ChangeNick(s) => {
+                    match change_nick(&users, &accounts, &account, &moderation, &nick, s).await {
+                        Ok(new) => {
+                            info!("{} [{}] changed their nick to {}", peer_address, nick, new);
+                            let old = std::mem::replace(&mut nick, new.clone());
+                            // users in other rooms may be talking to them privately
+                            tx.send((Msg::NickedNickChange(old, new), Target::Everyone))
+                                .await
+                        }
+                        Err(reason) => {
+                            tx.send((Msg::Error(reason), Target::User(nick.clone())))
+                                .await
+                        }
+                    }
+                }
+                Action::JoinRoom(new) => {
+                    {
+                        let mut users = users.lock().await;
+                        if let Some(user) = users.get_mut(&nick) {
+                            user.room = new.clone();
+                        }
+                        send_roster(&mut users, &nick, &new);
+                    }
+                    debug!("{} [{}] moved from {} to {}", peer_address, nick, room, new);
+                    let old = std::mem::replace(&mut room, new.clone());
+                    tx.send((
+                        Msg::NickedPart(nick.clone(), old.clone()),
+                        Target::Room(old),
+                    ))
+                    .await
+                    .unwrap();
+                    replay_history(&history, &room, &nick, &tx).await;
+                    tx.send((
+                        Msg::NickedJoin(nick.clone(), new.clone()),
+                        Target::Room(new),
+                    ))
+                    .await
+                }
+                Action::Register(password) => match register(&accounts, &nick, password).await {
+                    Ok(()) => {
+                        info!("{} [{}] registered their nick", peer_address, nick);
+                        account = Some(nick.clone());
+                        let reply = format!(
+                            "Registered {}. Log in with your password from now on.",
+                            nick
+                        );
+                        tx.send((Msg::ServerReply(reply), Target::User(nick.clone())))
+                            .await
+                    }
+                    Err(reason) => {
+                        tx.send((Msg::Error(reason), Target::User(nick.clone())))
+                            .await
+                    }
+                },
+                Action::ListBans => {
+                    let bans: Vec<String> = moderation
+                        .lock()
+                        .unwrap()
+                        .bans()
+                        .map(|ban| format!("  {}", ban))
+                        .collect();
+                    let mut replies = vec![format!("{} bans:", bans.len())];
+                    replies.extend(bans);
+                    let mut sent = Ok(());
+                    for reply in replies {
+                        let reply = (Msg::ServerReply(reply), Target::User(nick.clone()));
+                        sent = sent.and(tx.send(reply).await);
+                    }
+                    sent
+                }
+                Action::Quit => break 'receive,
+                action => {
+                    let result = moderate(action, &nick, &users, &moderation).await;
+                    if let Ok(done) = &result {
+                        info!("{} [{}]: {}", peer_address, nick, done);
+                    }
+                    let reply = result.map_or_else(Msg::Error, Msg::ServerReply);
+                    tx.send((reply, Target::User(nick.clone()))).await
+                }
+            }
+            .unwrap();
+        }
+    }
+
+    if shutting_down {
+        // the writer finishes the queue and closes the connection once every
+        // sender is gone, and everyone is leaving, so nobody is told about it
+        users.lock().await.remove(&nick);
+        // the queue may be full, and is only waited on as long as the shutdown is
+        let reason = config.shutdown_message.clone();
+        let notice = outbox.send(Msg::ServerShutdown(reason));
+        if !matches!(timeout(config.shutdown_timeout(), notice).await, Ok(Ok(()))) {
+            warn!(
+                "{} [{}] couldn't be told about the shutdown.",
+                peer_address, nick
+            );
+        }
+        drop(outbox);
+        writer_task.await.ok();
+        return;
+    }
+
+    info!("{} [{}] disconnected.", peer_address, nick);
+    users.lock().await.remove(&nick);
+    tx.send((Msg::NickedDisconnect(nick), Target::Room(room)))
+        .await
+        .unwrap();
+}
+
+/// Runs the handshake with a new connection, and validates the nick it sends and its
+/// password. Returns the nick, the agreed handshake and how the user logged in, or
+/// `None` if the connection was rejected.
+async fn log_in(
+    stream: &mut ChatStream,
+    users: &UsersType,
+    config: &Config,
+    identity: &Option<Arc<Identity>>,
+    accounts: &AccountsType,
+    moderation: &ModerationType,
+) -> Option<(Nick, Handshake, Login)> {
+    let peer_address = stream.peer_addr().unwrap();
+    let (capabilities, required): (&[Capability], &[Capability]) = if identity.is_some() {
+        (
+            &[
+                Capability::Encryption,
+                Capability::Fragmentation,
+                Capability::Timestamps,
+            ],
+            &[Capability::Encryption],
+        )
+    } else {
+        (&[Capability::Fragmentation, Capability::Timestamps], &[])
+    };
+    let handshake = match stream.server_hello(capabilities, required).await {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            info!("Rejected {} on hello: {}", peer_address, e);
+            return None;
+        }
+    };
+    debug!(
+        "{} speaks BCMP version {} with {:?}",
+        peer_address, handshake.version, handshake.capabilities
+    );
+
     let mut buffer = [0; MSG_LENGTH];
 
     let nick = match stream.receive_msg(&mut buffer).await {
         Ok(Msg::NickChange(nick)) => nick,
         _ => {
             warn!("{} aborted on nick.", peer_address);
-            return;
+            return None;
+        }
+    };
+    let nick = match Nick::new(nick) {
+        Ok(nick) => nick,
+        Err(e) => {
+            stream
+                .send_msg(&Msg::ConnectionRejected(e.to_string()))
+                .await
+                .unwrap_or(()); // do nothing, we don't need the user anyway
+            info!("Rejected {}, invalid nick: {}", peer_address, e);
+            return None;
         }
     };
 
+    let ban = moderation.lock().unwrap().nick_ban(&nick).cloned();
+    if let Some(ban) = ban {
+        stream
+            .send_msg(&Msg::ConnectionRejected(ban.explain()))
+            .await
+            .unwrap_or(()); // do nothing, we don't need the user anyway
+        info!("Rejected {}, {} is banned", peer_address, nick);
+        return None;
+    }
+
     {
         // lock users temporarily
         let userlock = users.lock().await;
-        if userlock.len() >= MAX_USERS {
+        if userlock.len() >= config.max_users {
             stream
                 .send_msg(&Msg::ConnectionRejected("too many users".into()))
                 .await
                 .unwrap_or(()); // do nothing, we don't need the user anyway
             info!("Rejected {}, too many users", peer_address);
-            return;
-        } else if userlock.contains_key(&nick) {
+            return None;
+        } else if commands::online_nick(&userlock, &nick).is_some() {
             stream
                 .send_msg(&Msg::ConnectionRejected("nick taken".into()))
                 .await
                 .unwrap_or(()); // do nothing, we don't need the user anyway
             info!("Rejected {}, nick taken", peer_address);
-            return;
+            return None;
         }
     }
-    let msg = if is_encrypted {
+    let msg = if identity.is_some() {
         Msg::ConnectionEncrypted
     } else {
         Msg::ConnectionAccepted
     };
 
     if let Err(e) = stream.send_msg(&msg).await {
-        warn!("Error accepting {}: {}", peer_address, e.to_string());
-        return;
+        warn!("Error accepting {}: {}", peer_address, e);
+        return None;
     }
 
-    if is_encrypted {
-        stream.encrypt().await.unwrap();
+    if let Some(identity) = identity {
+        if let Err(e) = stream.encrypt_server(identity).await {
+            warn!("Error encrypting stream from {}: {}", peer_address, e);
+            return None;
+        }
         debug!("Encrypted stream from {}", peer_address);
     }
 
-    info!("Connection successful from {}, nick {}", peer_address, nick);
-    tx.send((Msg::NickedConnect(nick.clone()), None))
+    // clients from before accounts can only join as guests
+    let authenticates = handshake.version >= Msg::Authenticated.version();
+    let password = if !authenticates {
+        String::new()
+    } else {
+        match stream.receive_msg(&mut buffer).await {
+            Ok(Msg::Authenticate(password)) => password,
+            _ => {
+                warn!("{} aborted on authentication.", peer_address);
+                return None;
+            }
+        }
+    };
+    let login = match authenticate(accounts, &nick, password).await {
+        Ok(login) => login,
+        Err(reason) => {
+            stream
+                .send_msg(&Msg::ConnectionRejected(reason.clone()))
+                .await
+                .unwrap_or(()); // do nothing, we don't need the user anyway
+            info!("Rejected {} [{}]: {}", peer_address, nick, reason);
+            return None;
+        }
+    };
+    if authenticates {
+        if let Err(e) = stream.send_msg(&Msg::Authenticated).await {
+            warn!("Error authenticating {}: {}", peer_address, e);
+            return None;
+        }
+    }
+
+    Some((nick, handshake, login))
+}
+
+/// Turns a connection away before the handshake, with the given reason.
+async fn reject(mut stream: ChatStream, reason: &str) {
+    info!("Rejected {}: {}", stream.peer_addr().unwrap(), reason);
+    stream
+        .send_msg(&Msg::ConnectionRejected(reason.into()))
         .await
-        .unwrap();
+        .unwrap_or(()); // do nothing, we don't need the user anyway
+}
 
-    let (mut reader, writer) = stream.into_split();
-    users.lock().await.insert(nick.clone(), writer);
+/// Checks a password for `nick`, which is needed if and only if the nick is registered.
+/// Returns how the user logged in, or the reason they can't.
+async fn authenticate(
+    accounts: &AccountsType,
+    nick: &Nick,
+    password: String,
+) -> Result<Login, String> {
+    let hash = match accounts {
+        Some(accounts) => accounts.lock().unwrap().password_hash(nick),
+        None => None,
+    };
+    match hash {
+        None if password.is_empty() => Ok(Login::Guest),
+        None => Ok(Login::Unregistered),
+        Some(_) if password.is_empty() => {
+            Err(format!("{} is registered, log in with its password", nick))
+        }
+        Some(hash) => {
+            let verify = move || accounts::verify_password(&password, &hash);
+            match tokio::task::spawn_blocking(verify).await {
+                Ok(true) => Ok(Login::Account),
+                _ => Err("wrong password".into()),
+            }
+        }
+    }
+}
 
-    loop {
-        let msg = match reader.receive_msg(&mut buffer).await {
-            Ok(msg) => msg,
+/// Registers `nick` with `password`, or returns the reason it can't be.
+async fn register(accounts: &AccountsType, nick: &Nick, password: String) -> Result<(), String> {
+    let accounts = accounts
+        .as_ref()
+        .ok_or_else(|| "this server doesn't have accounts".to_string())?;
+    if accounts.lock().unwrap().is_registered(nick) {
+        return Err(format!("{} is already registered", nick));
+    }
+    if password.chars().count() < accounts::MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "passwords must be at least {} characters long",
+            accounts::MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    let hash = tokio::task::spawn_blocking(move || accounts::hash_password(&password))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|hash| hash.map_err(|e| e.to_string()))
+        .map_err(|e| {
+            error!("Error hashing a password: {}", e);
+            "couldn't register your nick".to_string()
+        })?;
+
+    let mut accounts = accounts.lock().unwrap();
+    // someone may have registered it while the password was being hashed
+    if accounts.is_registered(nick) {
+        return Err(format!("{} is already registered", nick));
+    }
+    accounts.register(nick, hash).map_err(|e| {
+        error!("Error saving the accounts: {}", e);
+        "couldn't register your nick".to_string()
+    })
+}
+
+/// Sends the latest messages of `room` to a user who just joined it.
+async fn replay_history(
+    history: &HistoryType,
+    room: &Room,
+    nick: &Nick,
+    tx: &Sender<(Msg, Target)>,
+) {
+    let messages = match history {
+        Some(history) => history.lock().unwrap().replay(room),
+        None => return,
+    };
+    for msg in messages {
+        tx.send((msg, Target::User(nick.clone()))).await.unwrap();
+    }
+}
+
+/// Takes a snapshot of who is online, and in which room.
+async fn online_users(users: &UsersType) -> HashMap<Nick, Room> {
+    users
+        .lock()
+        .await
+        .iter()
+        .map(|(nick, user)| (nick.clone(), user.room.clone()))
+        .collect()
+}
+
+/// Queues the roster of `room` for `nick`, who has just joined it. This skips the
+/// router and is done with the users locked, so that any join or part the user was
+/// sent before the roster is already reflected in it.
+fn send_roster(users: &mut HashMap<Nick, User>, nick: &Nick, room: &Room) {
+    let mut roster: Vec<Nick> = users
+        .iter()
+        .filter(|(_, user)| user.room == *room)
+        .map(|(nick, _)| nick.clone())
+        .collect();
+    roster.sort_unstable();
+    if let Some(user) = users.get_mut(nick) {
+        user.send(Msg::Roster(room.clone(), roster));
+    }
+}
+
+/// Moves a user's writer from the `old` nick to the requested one, which must be valid,
+/// not banned, not taken by anyone else, and not registered unless it's the user's own
+/// `account`. Returns the new nick, or the reason it was refused.
+async fn change_nick(
+    users: &UsersType,
+    accounts: &AccountsType,
+    account: &Option<Nick>,
+    moderation: &ModerationType,
+    old: &Nick,
+    requested: String,
+) -> Result<Nick, String> {
+    let new = Nick::new(requested).map_err(|e| e.to_string())?;
+    if new == *old {
+        return Err(format!("you are already known as {}", new));
+    }
+    let registered = match accounts {
+        Some(accounts) => accounts.lock().unwrap().is_registered(&new),
+        None => false,
+    };
+    let owned = account
+        .as_ref()
+        .is_some_and(|account| accounts::same_account(account, &new));
+    if registered && !owned {
+        return Err(format!("nick {} is registered", new));
+    }
+    if moderation.lock().unwrap().nick_ban(&new).is_some() {
+        return Err(format!("nick {} is banned", new));
+    }
+
+    let mut users = users.lock().await;
+    // changing just the case of one's own nick is fine
+    if commands::online_nick(&users, &new).is_some_and(|taken| taken != old) {
+        return Err(format!("nick {} is taken", new));
+    }
+    let user = users
+        .remove(old)
+        .ok_or_else(|| "you are not connected".to_string())?;
+    users.insert(new.clone(), user);
+    moderation.lock().unwrap().rename(old, &new);
+    Ok(new)
+}
+
+/// Carries out an operator's kick, ban, unban, mute, unmute or (de)op. Returns what was
+/// done, or why it couldn't be.
+async fn moderate(
+    action: Action,
+    by: &Nick,
+    users: &UsersType,
+    moderation: &ModerationType,
+) -> Result<String, String> {
+    let mut users = users.lock().await;
+    let mut moderation = moderation.lock().unwrap();
+    let online = |nick: &Nick| format!("{} is not online anymore", nick);
+    let now = chrono::Utc::now();
+    let until = |duration: Option<chrono::Duration>| {
+        duration
+            .map(|duration| moderation::expiry(now, duration).ok_or("that's too long"))
+            .transpose()
+    };
+
+    match action {
+        Action::Kick(nick, reason) => {
+            let user = users.get_mut(&nick).ok_or_else(|| online(&nick))?;
+            let mut why = format!("kicked by {}", by);
+            if !reason.is_empty() {
+                why += &format!(": {}", reason);
+            }
+            user.remove(why);
+            Ok(format!("kicked {}", nick))
+        }
+        Action::Ban(target, duration, reason) => {
+            let ban = Ban {
+                target,
+                reason,
+                by: by.to_string(),
+                created: now,
+                expires: until(duration)?,
+            };
+            moderation.ban(ban.clone()).map_err(|e| {
+                error!("Error saving the bans: {}", e);
+                "couldn't save the ban".to_string()
+            })?;
+            let mut kicked = 0;
+            for (nick, user) in users.iter_mut() {
+                let banned = match &ban.target {
+                    BanTarget::Nick(banned) => nick.eq_ignore_ascii_case(banned),
+                    BanTarget::Network(network) => network.contains(&user.address),
+                };
+                if banned && nick != by {
+                    user.remove(ban.explain());
+                    kicked += 1;
+                }
+            }
+            match kicked {
+                0 => Ok(format!("banned {}", ban)),
+                1 => Ok(format!("banned {} (kicked 1 user)", ban)),
+                _ => Ok(format!("banned {} (kicked {} users)", ban, kicked)),
+            }
+        }
+        Action::Unban(target) => match moderation.unban(&target) {
+            Ok(true) => Ok(format!("lifted the ban of {}", target)),
+            Ok(false) => Err(format!("{} isn't banned", target)),
             Err(e) => {
-                info!("{} [{}] disconnected.", peer_address, nick);
-                debug!("Associated error: {}", e.to_string());
-                users.lock().await.remove(&nick);
-                tx.send((Msg::NickedDisconnect(nick), None)).await.unwrap();
-                break;
+                error!("Error saving the bans: {}", e);
+                Err("couldn't save the bans".into())
             }
-        };
-
-        trace!("Msg({}): [{}]: {}", msg.code(), nick, msg.string());
-        match msg {
-            Msg::UserMsg(s) => tx.send((Msg::NickedUserMsg(nick.clone(), s), None)).await,
-            Msg::NickChange(s) => {
-                tx.send((Msg::NickedNickChange(nick.clone(), s), None))
-                    .await
+        },
+        Action::Mute(nick, duration) => {
+            let user = users.get_mut(&nick).ok_or_else(|| online(&nick))?;
+            moderation.mute(&nick, until(duration)?);
+            let time = match duration {
+                Some(duration) => format!("for {}", moderation::format_duration(duration)),
+                None => "until you're unmuted".to_string(),
+            };
+            user.send(Msg::Notice(format!("You were muted by {} {}.", by, time)));
+            Ok(format!("muted {}", nick))
+        }
+        Action::Unmute(nick) => {
+            let user = users.get_mut(&nick).ok_or_else(|| online(&nick))?;
+            if !moderation.unmute(&nick) {
+                return Err(format!("{} isn't muted", nick));
             }
-            Msg::Command(s) => tx.send((Msg::NickedCommand(nick.clone(), s), None)).await,
-            _ => Ok(()),
+            user.send(Msg::Notice(format!("You were unmuted by {}.", by)));
+            Ok(format!("unmuted {}", nick))
         }
-        .unwrap();
+        Action::SetOperator(nick, operator) => {
+            let user = users.get_mut(&nick).ok_or_else(|| online(&nick))?;
+            if user.operator == operator {
+                let already = if operator { "already" } else { "not" };
+                return Err(format!("{} is {} an operator", nick, already));
+            }
+            user.operator = operator;
+            if operator {
+                user.send(Msg::Notice(format!("{} made you an operator.", by)));
+                Ok(format!("made {} an operator", nick))
+            } else {
+                user.send(Msg::Notice(format!(
+                    "{} took your operator status away.",
+                    by
+                )));
+                Ok(format!("took {}'s operator status away", nick))
+            }
+        }
+        _ => unreachable!("not a moderation action"),
     }
 }
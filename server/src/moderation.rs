@@ -0,0 +1,258 @@
+//! Bans and mutes, set by operators.
+//!
+//! Bans are kept in a JSON file, so that they survive restarts. They target a nick
+//! (regardless of its case), an IP address or a whole network in CIDR notation, and
+//! may expire. Mutes only last until the server restarts, and target the muted nick
+//! (regardless of its case, and following it through nick changes), so that others
+//! on the same address aren't silenced along with it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use chat_rs::Nick;
+
+use crate::files;
+
+/// The longest a ban or mute can last, about 10 years. Longer ones should be for good.
+pub const MAX_DURATION: Duration = Duration::weeks(520);
+
+/// Who a ban applies to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BanTarget {
+    /// A nick, lowercased.
+    Nick(String),
+    /// An address or a network.
+    Network(IpNet),
+}
+
+impl BanTarget {
+    /// Parses a nick, an IP address or a network in CIDR notation. Anything that looks
+    /// like an address is taken to be one.
+    pub fn parse(target: &str) -> Result<Self, String> {
+        if let Ok(network) = target.parse::<IpNet>() {
+            return Ok(BanTarget::Network(network.trunc()));
+        }
+        if let Ok(address) = target.parse::<IpAddr>() {
+            return Ok(BanTarget::Network(address.into()));
+        }
+        match Nick::new(target) {
+            Ok(_) => Ok(BanTarget::Nick(target.to_ascii_lowercase())),
+            Err(_) => Err(format!("{} is neither a nick nor an address", target)),
+        }
+    }
+}
+
+impl TryFrom<String> for BanTarget {
+    type Error = String;
+
+    fn try_from(target: String) -> Result<Self, Self::Error> {
+        Self::parse(&target)
+    }
+}
+
+impl From<BanTarget> for String {
+    fn from(target: BanTarget) -> Self {
+        target.to_string()
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Nick(nick) => f.write_str(nick),
+            // single addresses are shown without their prefix length
+            BanTarget::Network(network) if network.prefix_len() == network.max_prefix_len() => {
+                write!(f, "{}", network.addr())
+            }
+            BanTarget::Network(network) => write!(f, "{}", network),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    /// The nick of the operator who set the ban.
+    pub by: String,
+    pub created: DateTime<Utc>,
+    /// When the ban is lifted, or `None` if it's permanent.
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+
+    /// Explains the ban to the user it applies to.
+    pub fn explain(&self) -> String {
+        let mut explanation = String::from("you are banned from this server");
+        if !self.reason.is_empty() {
+            explanation += &format!(": {}", self.reason);
+        }
+        if let Some(expires) = self.expires {
+            explanation += &format!(" (until {})", expires.format("%Y-%m-%d %H:%M UTC"));
+        }
+        explanation
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} by {}", self.target, self.by)?;
+        match self.expires {
+            Some(expires) => write!(f, " until {}", expires.format("%Y-%m-%d %H:%M UTC"))?,
+            None => write!(f, " permanently")?,
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Moderation {
+    path: PathBuf,
+    bans: Vec<Ban>,
+    /// The muted nicks, lowercased, and when they're unmuted, unless it's indefinitely.
+    mutes: HashMap<String, Option<DateTime<Utc>>>,
+}
+
+impl Moderation {
+    /// Loads the bans from the file at `path`, which is created when the first ban is
+    /// set. Bans that have expired are dropped.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut bans: Vec<Ban> = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let now = Utc::now();
+        bans.retain(|ban| ban.is_active(now));
+        Ok(Moderation {
+            path: path.to_path_buf(),
+            bans,
+            mutes: HashMap::new(),
+        })
+    }
+
+    /// The bans currently in effect.
+    pub fn bans(&self) -> impl Iterator<Item = &Ban> {
+        let now = Utc::now();
+        self.bans.iter().filter(move |ban| ban.is_active(now))
+    }
+
+    /// Returns the ban that applies to `address`, if there is one.
+    pub fn address_ban(&self, address: IpAddr) -> Option<&Ban> {
+        self.bans().find(|ban| match &ban.target {
+            BanTarget::Network(network) => network.contains(&address),
+            BanTarget::Nick(_) => false,
+        })
+    }
+
+    /// Returns the ban that applies to `nick`, if there is one.
+    pub fn nick_ban(&self, nick: &str) -> Option<&Ban> {
+        self.bans().find(|ban| match &ban.target {
+            BanTarget::Nick(banned) => banned.eq_ignore_ascii_case(nick),
+            BanTarget::Network(_) => false,
+        })
+    }
+
+    /// Adds a ban, replacing any earlier ban of the same target, and saves the bans.
+    pub fn ban(&mut self, ban: Ban) -> io::Result<()> {
+        let now = Utc::now();
+        self.bans
+            .retain(|old| old.target != ban.target && old.is_active(now));
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Lifts the ban of `target`, and saves the bans. Returns whether it was banned.
+    pub fn unban(&mut self, target: &BanTarget) -> io::Result<bool> {
+        let count = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        if self.bans.len() == count {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Mutes `nick` until the given time, or until it's unmuted.
+    pub fn mute(&mut self, nick: &str, until: Option<DateTime<Utc>>) {
+        self.mutes.insert(nick.to_lowercase(), until);
+    }
+
+    /// Unmutes `nick`. Returns whether it was muted.
+    pub fn unmute(&mut self, nick: &str) -> bool {
+        self.mutes.remove(&nick.to_lowercase()).is_some()
+    }
+
+    pub fn is_muted(&mut self, nick: &str) -> bool {
+        let nick = nick.to_lowercase();
+        match self.mutes.get(&nick) {
+            Some(Some(until)) if *until <= Utc::now() => {
+                self.mutes.remove(&nick);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Carries a mute over to the new nick of a user who changed it.
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(until) = self.mutes.remove(&old.to_lowercase()) {
+            self.mutes.insert(new.to_lowercase(), until);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        files::replace_json(&self.path, &self.bans)
+    }
+}
+
+/// Parses a duration like `30s`, `10m`, `2h`, `7d` or `4w`, of up to `MAX_DURATION`.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let unit = duration.chars().last()?;
+    let amount: i64 = duration[..duration.len() - unit.len_utf8()].parse().ok()?;
+    if amount <= 0 {
+        return None;
+    }
+    let duration = match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => None,
+    }?;
+    (duration <= MAX_DURATION).then_some(duration)
+}
+
+/// Returns when something that lasts `duration` from `now` is over, or `None` if that's
+/// beyond what the clock can tell.
+pub fn expiry(now: DateTime<Utc>, duration: Duration) -> Option<DateTime<Utc>> {
+    now.checked_add_signed(duration)
+}
+
+/// Formats a duration in the biggest unit that divides it, e.g. two hours as `2h` and
+/// 90 minutes as `90m`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    let units = [(604800, 'w'), (86400, 'd'), (3600, 'h'), (60, 'm')];
+    match units.iter().find(|(size, _)| seconds % size == 0) {
+        Some((size, unit)) => format!("{}{}", seconds / size, unit),
+        None => format!("{}s", seconds),
+    }
+}
@@ -0,0 +1,415 @@
+//! Per-direction cipher state for encrypted BCMP sessions.
+//!
+//! Every encrypted frame is sealed with a nonce derived from the direction it travels
+//! in and a sequence number counting the frames sent in that direction. The receiver
+//! knows which nonce to expect next, so replayed, dropped, reordered or reflected
+//! frames are rejected instead of being accepted as fresh messages.
+//!
+//! Each direction has its own key, and the keys are periodically replaced using a
+//! fresh ECDH carried in `Msg::Rekey` messages:
+//! 1. Whichever side reaches its `RekeyPolicy` limits first sends a `Rekey` containing
+//!    a new ephemeral public key.
+//! 2. The other side answers with a `Rekey` containing its own new public key, unless
+//!    it already sent one itself. Both sides can now derive the new keys.
+//! 3. Each side sends an empty `Rekey` as the last frame under its old sending key,
+//!    and switches to the new one. The receiver switches keys right after it.
+//!
+//! A rekey completes the next time the other side sends something, so the old keys
+//! stay in use until then.
+
+use std::sync::{Arc, Mutex};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::{AeadInPlace, Aes256Gcm, KeyInit};
+use k256::ecdh::EphemeralSecret;
+use k256::{EncodedPoint, PublicKey};
+use rand_core::OsRng;
+use sha2::Sha256;
+
+use crate::{ChatError, Result, NONCE_SIZE};
+
+/// The direction a frame travels in, which is encoded in its nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    pub(crate) fn opposite(self) -> Self {
+        match self {
+            Direction::ClientToServer => Direction::ServerToClient,
+            Direction::ServerToClient => Direction::ClientToServer,
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Direction::ClientToServer => 0,
+            Direction::ServerToClient => 1,
+        }
+    }
+
+    /// The HKDF info label used to derive the key for this direction.
+    fn label(self) -> &'static [u8] {
+        match self {
+            Direction::ClientToServer => b"chat-rs client-to-server",
+            Direction::ServerToClient => b"chat-rs server-to-client",
+        }
+    }
+}
+
+fn nonce(direction: Direction, sequence: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[0] = direction.tag();
+    nonce[4..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+/// Derives the key for each direction from an ECDH, returning the keys for sending
+/// in `outgoing` and for receiving in the opposite direction.
+fn derive_keys(
+    my_secret: &EphemeralSecret,
+    other_public: &PublicKey,
+    outgoing: Direction,
+) -> (Aes256Gcm, Aes256Gcm) {
+    let shared = my_secret.diffie_hellman(other_public);
+    let hk = shared.extract::<Sha256>(None);
+
+    let derive = |direction: Direction| {
+        let mut key = [0u8; 32];
+        hk.expand(direction.label(), &mut key)
+            .expect("hk.expand got invalid length - this should never ever happen!");
+        Aes256Gcm::new(GenericArray::from_slice(&key))
+    };
+
+    (derive(outgoing), derive(outgoing.opposite()))
+}
+
+/// Sets up the ciphers of a freshly encrypted session, where `outgoing` is the
+/// direction of the frames we send.
+pub(crate) fn new_session(
+    my_secret: &EphemeralSecret,
+    other_public: &PublicKey,
+    outgoing: Direction,
+    policy: RekeyPolicy,
+) -> (SendCipher, ReceiveCipher) {
+    let (send, receive) = derive_keys(my_secret, other_public, outgoing);
+    let rekey = Arc::new(Mutex::new(Rekey {
+        outgoing,
+        pending: None,
+        publish: None,
+        next_send: None,
+        next_receive: None,
+    }));
+
+    let send = SendCipher {
+        cipher: send,
+        direction: outgoing,
+        sequence: 0,
+        policy,
+        frames: 0,
+        bytes: 0,
+        rekey: rekey.clone(),
+    };
+    let receive = ReceiveCipher {
+        cipher: receive,
+        direction: outgoing.opposite(),
+        sequence: 0,
+        rekey,
+    };
+    (send, receive)
+}
+
+/// Limits on how much a key may be used for before a rekey is started.
+/// Both are counted per direction, since the last time the key was replaced.
+///
+/// Rekeys happen in the middle of a conversation, whichever side starts them:
+/// ```
+/// use chat_rs::{ChatStream, Identity, Msg, ReceiveMsg, RekeyPolicy, SendMsg, MSG_LENGTH};
+///
+/// #[tokio::main]
+/// async fn main() -> chat_rs::Result<()> {
+///     let often = RekeyPolicy { max_frames: 3, max_bytes: 1 << 20 };
+///     let policies = [
+///         (often, RekeyPolicy::default()),
+///         (RekeyPolicy::default(), often),
+///         (often, often),
+///     ];
+///     for (client_policy, server_policy) in policies {
+///         let (client, server) = tokio::io::duplex(4 * MSG_LENGTH);
+///         let mut client = ChatStream::new(client);
+///         let mut server = ChatStream::new(server);
+///         let identity = Identity::generate();
+///         tokio::try_join!(client.encrypt_client(), server.encrypt_server(&identity))?;
+///         client.set_rekey_policy(client_policy);
+///         server.set_rekey_policy(server_policy);
+///
+///         let mut buffer = [0u8; MSG_LENGTH];
+///         for i in 0..20 {
+///             client.send_msg(&Msg::UserMsg(format!("ping {}", i))).await?;
+///             let ping = server.receive_msg(&mut buffer).await?;
+///             assert_eq!(ping.string(), format!("ping {}", i));
+///
+///             server.send_msg(&Msg::ServerReply(format!("pong {}", i))).await?;
+///             let pong = client.receive_msg(&mut buffer).await?;
+///             assert_eq!(pong.string(), format!("pong {}", i));
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    pub max_frames: u64,
+    pub max_bytes: u64,
+}
+
+impl RekeyPolicy {
+    fn is_due(&self, frames: u64, bytes: u64) -> bool {
+        frames >= self.max_frames || bytes >= self.max_bytes
+    }
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            max_frames: 1000,
+            max_bytes: 1 << 20,
+        }
+    }
+}
+
+/// The state of a rekey, shared between the sending and receiving ciphers since
+/// they're usually owned by different tasks after `ChatStream::into_split`.
+struct Rekey {
+    outgoing: Direction,
+    /// Our new ephemeral secret, sent to the other side and awaiting its answer.
+    pending: Option<EphemeralSecret>,
+    /// A new public key of ours that still has to be sent.
+    publish: Option<String>,
+    /// The next sending key, to be switched to after sending an empty `Rekey`.
+    next_send: Option<Aes256Gcm>,
+    /// The next receiving key, to be switched to after receiving an empty `Rekey`.
+    next_receive: Option<Aes256Gcm>,
+}
+
+impl Rekey {
+    fn is_idle(&self) -> bool {
+        self.pending.is_none()
+            && self.publish.is_none()
+            && self.next_send.is_none()
+            && self.next_receive.is_none()
+    }
+}
+
+fn encode_public(secret: &EphemeralSecret) -> String {
+    hex::encode(EncodedPoint::from(&secret.public_key()).as_bytes())
+}
+
+/// A rekey message the sending side must send before its next frame.
+pub(crate) enum RekeyStep {
+    /// Send a `Rekey` with our new public key.
+    Publish(String),
+    /// Send an empty `Rekey`, then switch to the new sending key.
+    Switch(Box<Aes256Gcm>),
+}
+
+/// The cipher state used to encrypt outgoing frames.
+pub struct SendCipher {
+    cipher: Aes256Gcm,
+    direction: Direction,
+    sequence: u64,
+    policy: RekeyPolicy,
+    frames: u64,
+    bytes: u64,
+    rekey: Arc<Mutex<Rekey>>,
+}
+
+impl SendCipher {
+    pub(crate) fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the rekey messages that have to be sent before the next frame,
+    /// starting a new rekey if the current key is due for replacement.
+    pub(crate) fn rekey_steps(&mut self) -> Vec<RekeyStep> {
+        let mut rekey = self.rekey.lock().unwrap();
+        let mut steps = Vec::new();
+
+        if let Some(public) = rekey.publish.take() {
+            steps.push(RekeyStep::Publish(public));
+        }
+        if let Some(key) = rekey.next_send.take() {
+            steps.push(RekeyStep::Switch(Box::new(key)));
+        } else if rekey.is_idle() && self.policy.is_due(self.frames, self.bytes) {
+            let secret = EphemeralSecret::random(&mut OsRng);
+            steps.push(RekeyStep::Publish(encode_public(&secret)));
+            rekey.pending = Some(secret);
+        }
+
+        steps
+    }
+
+    /// Replaces the sending key, which must happen right after sealing an empty `Rekey`.
+    pub(crate) fn switch(&mut self, key: Aes256Gcm) {
+        self.cipher = key;
+        self.frames = 0;
+        self.bytes = 0;
+    }
+
+    /// Encrypts `buffer` in place as the next frame, returning the nonce it was sealed with.
+    pub(crate) fn seal(&mut self, buffer: &mut Vec<u8>) -> Result<[u8; NONCE_SIZE]> {
+        let nonce = nonce(self.direction, self.sequence);
+        self.sequence = match self.sequence.checked_add(1) {
+            Some(sequence) => sequence,
+            None => return Err(ChatError::SequenceExhausted),
+        };
+        self.frames += 1;
+        self.bytes += buffer.len() as u64;
+
+        let length = buffer.len();
+        self.cipher
+            .encrypt_in_place(GenericArray::from_slice(&nonce), &[], buffer)
+            .map_err(|_| ChatError::MessageTooLarge(length))?;
+        Ok(nonce)
+    }
+}
+
+/// The cipher state used to decrypt incoming frames.
+///
+/// Frames that are replayed, reordered or sent back to where they came from are
+/// rejected, even though they were sealed with the right key:
+/// ```
+/// use chat_rs::{ChatError, ChatStream, Identity, Msg, ReceiveMsg, SendMsg, MSG_LENGTH};
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+///
+/// # async fn pair() -> chat_rs::Result<(ChatStream<DuplexStream>, ChatStream<DuplexStream>)> {
+/// #     let (client, server) = tokio::io::duplex(4 * MSG_LENGTH);
+/// #     let mut client = ChatStream::new(client);
+/// #     let mut server = ChatStream::new(server);
+/// #     let identity = Identity::generate();
+/// #     tokio::try_join!(client.encrypt_client(), server.encrypt_server(&identity))?;
+/// #     Ok((client, server))
+/// # }
+/// #
+/// /// Takes the next encrypted frame off the wire, as an attacker could.
+/// async fn intercept(wire: &mut DuplexStream) -> std::io::Result<Vec<u8>> {
+///     let length = wire.read_u16().await?;
+///     let mut frame = length.to_be_bytes().to_vec();
+///     frame.resize(2 + 12 + length as usize, 0);
+///     wire.read_exact(&mut frame[2..]).await?;
+///     Ok(frame)
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> chat_rs::Result<()> {
+///     let mut buffer = [0u8; MSG_LENGTH];
+///
+///     // a frame that arrives twice
+///     let (mut client, mut server) = pair().await?;
+///     client.send_msg(&Msg::UserMsg("hello".into())).await?;
+///     let hello = intercept(&mut server.inner).await?;
+///     client.inner.write_all(&hello).await?;
+///     client.inner.write_all(&hello).await?;
+///     assert_eq!(server.receive_msg(&mut buffer).await?.string(), "hello");
+///     let replayed = server.receive_msg(&mut buffer).await;
+///     assert!(matches!(replayed, Err(ChatError::Replayed(0))));
+///
+///     // frames that arrive in the wrong order
+///     let (mut client, mut server) = pair().await?;
+///     client.send_msg(&Msg::UserMsg("first".into())).await?;
+///     client.send_msg(&Msg::UserMsg("second".into())).await?;
+///     let first = intercept(&mut server.inner).await?;
+///     let second = intercept(&mut server.inner).await?;
+///     client.inner.write_all(&second).await?;
+///     client.inner.write_all(&first).await?;
+///     let reordered = server.receive_msg(&mut buffer).await;
+///     assert!(matches!(
+///         reordered,
+///         Err(ChatError::OutOfOrder { expected: 0, received: 1 })
+///     ));
+///
+///     // a frame of the server's, reflected back at it
+///     let (mut client, mut server) = pair().await?;
+///     server.send_msg(&Msg::ServerReply("hello".into())).await?;
+///     let reply = intercept(&mut client.inner).await?;
+///     client.inner.write_all(&reply).await?;
+///     let reflected = server.receive_msg(&mut buffer).await;
+///     assert!(matches!(reflected, Err(ChatError::WrongDirection)));
+///
+///     Ok(())
+/// }
+/// ```
+pub struct ReceiveCipher {
+    cipher: Aes256Gcm,
+    direction: Direction,
+    sequence: u64,
+    rekey: Arc<Mutex<Rekey>>,
+}
+
+impl ReceiveCipher {
+    /// Decrypts `buffer` in place, which must be the next frame in sequence.
+    pub(crate) fn open(&mut self, received_nonce: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        let expected = nonce(self.direction, self.sequence);
+        if received_nonce != expected {
+            if received_nonce[..4] != expected[..4] {
+                return Err(ChatError::WrongDirection);
+            }
+            let mut sequence = [0u8; 8];
+            sequence.copy_from_slice(&received_nonce[4..]);
+            let sequence = u64::from_be_bytes(sequence);
+            if sequence < self.sequence {
+                return Err(ChatError::Replayed(sequence));
+            }
+            return Err(ChatError::OutOfOrder {
+                expected: self.sequence,
+                received: sequence,
+            });
+        }
+
+        self.cipher
+            .decrypt_in_place(GenericArray::from_slice(&expected), &[], buffer)
+            .map_err(|_| ChatError::Decrypt)?;
+        self.sequence += 1;
+        Ok(())
+    }
+
+    /// Handles the contents of a received `Msg::Rekey`.
+    pub(crate) fn handle_rekey(&mut self, public: &str) -> Result<()> {
+        let mut rekey = self.rekey.lock().unwrap();
+
+        if public.is_empty() {
+            self.cipher = rekey
+                .next_receive
+                .take()
+                .ok_or(ChatError::Protocol("unexpected rekey switch"))?;
+            return Ok(());
+        }
+
+        if rekey.next_receive.is_some() {
+            return Err(ChatError::Protocol(
+                "rekey while another one is in progress",
+            ));
+        }
+        let other_public = hex::decode(public)
+            .ok()
+            .and_then(|public| PublicKey::from_sec1_bytes(&public).ok())
+            .ok_or(ChatError::Protocol("invalid rekey public key"))?;
+
+        // If we didn't start this rekey ourselves, answer with a new key of our own.
+        let secret = match rekey.pending.take() {
+            Some(secret) => secret,
+            None => {
+                let secret = EphemeralSecret::random(&mut OsRng);
+                rekey.publish = Some(encode_public(&secret));
+                secret
+            }
+        };
+
+        let (send, receive) = derive_keys(&secret, &other_public, rekey.outgoing);
+        rekey.next_send = Some(send);
+        rekey.next_receive = Some(receive);
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! A `tokio_util` codec for BCMP, turning a transport into a `Stream` of received
+//! messages and a `Sink` of messages to send.
+//!
+//! The codec speaks the same framing as `SendMsg` and `ReceiveMsg`, including
+//! encryption, fragments and rekeys, so it's meant to take over a `ChatStream` (or
+//! one of its halves) once the hello exchange and `encrypt_*` are done. See
+//! `ChatStream::into_framed`.
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+use crate::{
+    accept_frame, encode_outgoing, split_frame, ChatError, Framing, Msg, ReceiveCipher, Result,
+    SendCipher, MSG_LENGTH, NONCE_SIZE, TAG_SIZE,
+};
+
+/// The read half of a `ChatStream`, as a `Stream` of received messages.
+pub type FramedReader<T = TcpStream> = FramedRead<ReadHalf<T>, BcmpCodec>;
+/// The write half of a `ChatStream`, as a `Sink` of messages to send.
+pub type FramedWriter<T = TcpStream> = FramedWrite<WriteHalf<T>, BcmpCodec>;
+
+/// A `Decoder` and `Encoder<Msg>` for BCMP frames.
+///
+/// A codec created with `BcmpCodec::new` speaks plaintext BCMP without fragmentation;
+/// the ones created by the `into_framed` methods carry over the cipher state and
+/// framing settings negotiated on the stream.
+pub struct BcmpCodec {
+    send_cipher: Option<SendCipher>,
+    receive_cipher: Option<ReceiveCipher>,
+    framing: Framing,
+    fragments: Vec<u8>,
+}
+
+impl BcmpCodec {
+    pub fn new() -> Self {
+        Self::with_state(None, None, Framing::default())
+    }
+
+    pub(crate) fn with_state(
+        send_cipher: Option<SendCipher>,
+        receive_cipher: Option<ReceiveCipher>,
+        framing: Framing,
+    ) -> Self {
+        BcmpCodec {
+            send_cipher,
+            receive_cipher,
+            framing,
+            fragments: Vec::new(),
+        }
+    }
+
+    /// Splits the next complete frame off `src`, returning it without any encryption,
+    /// or `None` if it hasn't been fully received yet.
+    fn next_frame(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        if let Some(cipher) = self.receive_cipher.as_mut() {
+            if src.len() < 2 {
+                return Ok(None);
+            }
+            let clen = u16::from_be_bytes([src[0], src[1]]) as usize;
+
+            if clen > MSG_LENGTH + TAG_SIZE {
+                return Err(ChatError::FrameTooLarge(clen));
+            }
+
+            let total = 2 + NONCE_SIZE + clen;
+            if src.len() < total {
+                src.reserve(total - src.len());
+                return Ok(None);
+            }
+
+            let frame = src.split_to(total);
+            let mut plaintext = frame[2 + NONCE_SIZE..].to_vec();
+            cipher.open(&frame[2..2 + NONCE_SIZE], &mut plaintext)?;
+            return Ok(Some(plaintext));
+        }
+
+        if src.len() < 3 {
+            return Ok(None);
+        }
+        let (_, length) = Msg::parse_header(&src[0..3]);
+
+        if length + 3 > MSG_LENGTH {
+            return Err(ChatError::FrameTooLarge(length + 3));
+        }
+
+        if src.len() < length + 3 {
+            src.reserve(length + 3 - src.len());
+            return Ok(None);
+        }
+
+        let frame = src[..length + 3].to_vec();
+        src.advance(length + 3);
+        Ok(Some(frame))
+    }
+}
+
+impl Default for BcmpCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for BcmpCodec {
+    type Item = Msg;
+    type Error = ChatError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Msg>> {
+        while let Some(frame) = self.next_frame(src)? {
+            let (code, payload) = split_frame(&frame)?;
+
+            let msg = accept_frame(
+                code,
+                payload,
+                self.framing,
+                self.receive_cipher.as_mut(),
+                &mut self.fragments,
+            )?;
+            if msg.is_some() {
+                return Ok(msg);
+            }
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Msg>> {
+        match self.decode(src)? {
+            Some(msg) => Ok(Some(msg)),
+            None if src.is_empty() && self.fragments.is_empty() => Ok(None),
+            None => Err(ChatError::Closed),
+        }
+    }
+}
+
+impl Encoder<Msg> for BcmpCodec {
+    type Error = ChatError;
+
+    fn encode(&mut self, msg: Msg, dst: &mut BytesMut) -> Result<()> {
+        let mut wire = Vec::with_capacity(MSG_LENGTH);
+        encode_outgoing(&msg, self.framing, self.send_cipher.as_mut(), &mut wire)?;
+        dst.extend_from_slice(&wire);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for BcmpCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BcmpCodec")
+    }
+}
@@ -0,0 +1,127 @@
+//! The error type of the crate.
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// A `Result` whose error defaults to `ChatError`.
+pub type Result<T, E = ChatError> = std::result::Result<T, E>;
+
+/// An error that occurred while speaking BCMP.
+#[derive(Debug, Error)]
+pub enum ChatError {
+    /// The underlying transport failed.
+    #[error("I/O error: {0}")]
+    Io(#[source] io::Error),
+    /// The other side closed the connection, possibly in the middle of a frame.
+    #[error("connection closed")]
+    Closed,
+    /// A received frame is longer than BCMP allows.
+    #[error("received a frame that is too large ({0} bytes)")]
+    FrameTooLarge(usize),
+    /// A message is too large to be sent, or a fragmented message being received
+    /// is over the size limit.
+    #[error("message too large ({0} bytes)")]
+    MessageTooLarge(usize),
+    /// A received frame has a message code this crate doesn't know about.
+    #[error("received an unknown message code ({0})")]
+    UnknownCode(u8),
+    /// A received message has a payload that doesn't make sense for its code.
+    #[error("received a malformed message (code {0})")]
+    MalformedPayload(u8),
+    /// A received message has a payload that isn't valid UTF-8.
+    #[error("received a message that isn't valid UTF-8")]
+    InvalidUtf8,
+    /// A received frame failed authentication, meaning it was corrupted or forged.
+    #[error("failed to decrypt a frame")]
+    Decrypt,
+    /// A received frame was sealed for the opposite direction, i.e. it was reflected
+    /// back at us.
+    #[error("received a frame sent in the wrong direction")]
+    WrongDirection,
+    /// A received frame was already received before.
+    #[error("received a replayed frame (sequence number {0})")]
+    Replayed(u64),
+    /// A received frame isn't the next one in sequence, meaning frames were dropped
+    /// or reordered.
+    #[error("received a frame out of order (expected sequence number {expected}, got {received})")]
+    OutOfOrder { expected: u64, received: u64 },
+    /// The session sent so many frames that its nonces would repeat.
+    #[error("exhausted the frame sequence numbers of this session")]
+    SequenceExhausted,
+    /// The other side broke the framing or rekeying rules.
+    #[error("protocol violation: {0}")]
+    Protocol(&'static str),
+    /// A nick doesn't follow the rules of `Nick`, for the given reason.
+    #[error("{0}")]
+    InvalidNick(&'static str),
+    /// A room name doesn't follow the rules of `Room`, for the given reason.
+    #[error("{0}")]
+    InvalidRoom(&'static str),
+    /// The hello exchange or the key exchange failed.
+    #[error("handshake failed: {0}")]
+    BadHandshake(String),
+    /// The server rejected the connection, for the given reason.
+    #[error("server refused connection: {0}")]
+    Rejected(String),
+    /// The server's identity signature over the key exchange doesn't verify.
+    #[error("server identity signature is invalid")]
+    BadSignature,
+    /// The server's identity doesn't match the one pinned in `KnownHosts`.
+    #[error(
+        "WARNING: THE IDENTITY OF {host} HAS CHANGED!\n\
+         Someone could be impersonating the server, or its key was replaced.\n\
+         Pinned fingerprint:   {pinned}\n\
+         Received fingerprint: {received}\n\
+         If the change is expected, remove the host's entry from {}.",
+        .path.display()
+    )]
+    HostKeyChanged {
+        host: String,
+        pinned: String,
+        received: String,
+        path: PathBuf,
+    },
+    /// The server didn't encrypt the connection, although its identity is pinned in
+    /// `KnownHosts`, so the encryption may have been stripped by an attacker.
+    #[error(
+        "WARNING: {host} DID NOT ENCRYPT THE CONNECTION, ALTHOUGH ITS IDENTITY IS PINNED!\n\
+         Someone could be tampering with the connection, or the server stopped encrypting.\n\
+         If the change is expected, remove the host's entry from {}.",
+        .path.display()
+    )]
+    EncryptionStripped { host: String, path: PathBuf },
+    /// The server didn't encrypt the connection, and the user didn't allow that.
+    #[error("{0} does not encrypt the connection, and unencrypted connections aren't allowed")]
+    Unencrypted(String),
+    /// An identity file exists, but doesn't contain a valid key.
+    #[error("{} is not a valid identity file", .0.display())]
+    InvalidIdentity(PathBuf),
+}
+
+impl ChatError {
+    /// Returns whether the error means someone tampered with the encrypted session,
+    /// as opposed to a disconnect or a misbehaving peer.
+    pub fn is_tampering(&self) -> bool {
+        matches!(
+            self,
+            ChatError::Decrypt
+                | ChatError::WrongDirection
+                | ChatError::Replayed(_)
+                | ChatError::OutOfOrder { .. }
+                | ChatError::BadSignature
+                | ChatError::HostKeyChanged { .. }
+                | ChatError::EncryptionStripped { .. }
+        )
+    }
+}
+
+impl From<io::Error> for ChatError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => ChatError::Closed,
+            _ => ChatError::Io(e),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Long-term server identities, and the trust-on-first-use store clients use
+//! to pin them.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use k256::ecdsa::signature::{Signer, Verifier};
+use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+
+use crate::{ChatError, Result};
+
+/// The length of an encoded (compressed SEC1) identity public key.
+pub const IDENTITY_PUBLIC_LEN: usize = 33;
+/// The length of an encoded identity signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// A long-term signing key used by the server to authenticate itself during
+/// `ChatStream::encrypt_server`.
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    /// Generates a fresh random identity.
+    pub fn generate() -> Self {
+        Identity {
+            key: SigningKey::random(&mut OsRng),
+        }
+    }
+
+    /// Loads the hex-encoded identity stored at `path`, or generates a new one and
+    /// stores it there if the file doesn't exist yet.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            let contents = fs::read_to_string(path)?;
+            let key = hex::decode(contents.trim())
+                .ok()
+                .and_then(|bytes| SigningKey::from_slice(&bytes).ok())
+                .ok_or_else(|| ChatError::InvalidIdentity(path.to_path_buf()))?;
+            return Ok(Identity { key });
+        }
+
+        let identity = Self::generate();
+        let mut file = create_private(path)?;
+        writeln!(file, "{}", hex::encode(identity.key.to_bytes()))?;
+        Ok(identity)
+    }
+
+    /// Returns the public half of this identity.
+    pub fn public_key(&self) -> VerifyingKey {
+        *self.key.verifying_key()
+    }
+
+    /// Returns the fingerprint of this identity, as shown to clients.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> Signature {
+        self.key.sign(message)
+    }
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// Returns the fingerprint of an identity public key: the SHA-256 hash of its
+/// compressed encoding, in hex.
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    let hash = Sha256::digest(key.to_encoded_point(true).as_bytes());
+    format!("SHA256:{}", hex::encode(hash))
+}
+
+pub(crate) fn encode_public(key: &VerifyingKey) -> Vec<u8> {
+    key.to_encoded_point(true).as_bytes().to_vec()
+}
+
+pub(crate) fn verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<VerifyingKey> {
+    let key = VerifyingKey::from_sec1_bytes(public)
+        .map_err(|_| ChatError::BadHandshake("invalid server identity key".into()))?;
+    let signature = Signature::from_slice(signature).map_err(|_| ChatError::BadSignature)?;
+    key.verify(message, &signature)
+        .map_err(|_| ChatError::BadSignature)?;
+    Ok(key)
+}
+
+/// The result of checking a server's identity against the known hosts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    /// The identity matches the one pinned for this host.
+    Trusted,
+    /// The host hasn't been seen before, and its identity is now pinned.
+    Pinned,
+}
+
+/// A store of pinned server identities, one `host fingerprint` pair per line,
+/// in the spirit of SSH's `known_hosts`.
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Vec<(String, String)>,
+}
+
+impl KnownHosts {
+    /// Returns the path of the known hosts file shared by the chat-rs clients,
+    /// `~/.chat-rs/known_hosts`.
+    pub fn default_path() -> PathBuf {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+        match home {
+            Some(home) => PathBuf::from(home).join(".chat-rs").join("known_hosts"),
+            None => PathBuf::from("known_hosts"),
+        }
+    }
+
+    /// Loads the known hosts file at `path`. A missing file is treated as empty.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let hosts = contents
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let host = parts.next()?;
+                let fingerprint = parts.next()?;
+                Some((host.to_string(), fingerprint.to_string()))
+            })
+            .collect();
+
+        Ok(KnownHosts { path, hosts })
+    }
+
+    /// Loads the known hosts file from `KnownHosts::default_path()`.
+    pub fn load_default() -> Result<Self> {
+        Self::load(Self::default_path())
+    }
+
+    /// Checks `key` against the identity pinned for `host`, pinning it if the host
+    /// is unknown. Fails loudly if a different identity is pinned, since that means
+    /// either the server's key was replaced or someone is impersonating it.
+    pub fn verify(&mut self, host: &str, key: &VerifyingKey) -> Result<HostKeyStatus> {
+        let fingerprint = fingerprint(key);
+        match self.hosts.iter().find(|(known, _)| known == host) {
+            Some((_, pinned)) if *pinned == fingerprint => Ok(HostKeyStatus::Trusted),
+            Some((_, pinned)) => Err(ChatError::HostKeyChanged {
+                host: host.to_string(),
+                pinned: pinned.clone(),
+                received: fingerprint,
+                path: self.path.clone(),
+            }),
+            None => {
+                self.hosts.push((host.to_string(), fingerprint));
+                self.save()?;
+                Ok(HostKeyStatus::Pinned)
+            }
+        }
+    }
+
+    /// Checks whether the connection to `host` may go on unencrypted, which is only the
+    /// case if the user `allowed` it, and never for hosts with a pinned identity: those
+    /// encrypted before, so someone may have stripped the encryption.
+    pub fn check_unencrypted(&self, host: &str, allowed: bool) -> Result<()> {
+        if self.hosts.iter().any(|(known, _)| known == host) {
+            return Err(ChatError::EncryptionStripped {
+                host: host.to_string(),
+                path: self.path.clone(),
+            });
+        }
+        if !allowed {
+            return Err(ChatError::Unencrypted(host.to_string()));
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = String::new();
+        for (host, fingerprint) in &self.hosts {
+            contents.push_str(host);
+            contents.push(' ');
+            contents.push_str(fingerprint);
+            contents.push('\n');
+        }
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+}
@@ -4,42 +4,151 @@
 //! messages, e.g. `ChatStream` and `Msg`.
 
 use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use aes_gcm::aead::generic_array::GenericArray;
-use aes_gcm::aead::Aead;
-use aes_gcm::{AeadCore, AeadInPlace, Aes256Gcm, KeyInit};
-use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use k256::PublicKey;
 use k256::{ecdh::EphemeralSecret, EncodedPoint};
 use rand_core::OsRng;
-use sha2::Sha256;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+
+mod cipher;
+mod codec;
+mod error;
+mod identity;
+mod nick;
+mod room;
+mod roster;
+use cipher::{Direction, RekeyStep};
+pub use cipher::{ReceiveCipher, RekeyPolicy, SendCipher};
+pub use codec::{BcmpCodec, FramedReader, FramedWriter};
+pub use error::{ChatError, Result};
+pub use identity::*;
+pub use k256::ecdsa::VerifyingKey;
+pub use nick::{Nick, MAX_NICK_LENGTH};
+pub use room::{Room, DEFAULT_ROOM};
+pub use roster::Roster;
 
 /// The default maximum message length used between the
 /// client and the server, according to BCMP.
 pub const MSG_LENGTH: usize = 512 + 2 + NONCE_SIZE; // 512 + crypto length header + nonce
 pub const NONCE_SIZE: usize = 12;
 pub const ECDH_PUBLIC_LEN: usize = 33;
+const TAG_SIZE: usize = 16;
+
+/// The discriminant of a fragment frame, which is followed by more fragments.
+pub const FRAGMENT_CODE: u8 = 250;
+/// The discriminant of the fragment frame that completes a fragmented message.
+pub const LAST_FRAGMENT_CODE: u8 = 251;
+const FRAGMENT_LENGTH: usize = MSG_LENGTH - 3;
+/// The default limit on the size of a message reassembled from fragments.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// The BCMP version implemented by this crate. This is bumped whenever messages are
+/// added or the wire format changes, and peers only send each other the messages
+/// of the version they agreed on (see `Msg::version`).
+pub const PROTOCOL_VERSION: u16 = 17;
+/// The oldest BCMP version this crate is still able to speak. This is only raised
+/// when the wire format changes in a way that can't be negotiated, like the
+/// encryption changes of version 4.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+/// The first BCMP version whose key exchange signature covers the hellos. Encryption
+/// is only negotiated from this version on, since a man in the middle could otherwise
+/// strip capabilities or lower the version without either side noticing.
+pub const SIGNED_HELLO_VERSION: u16 = 17;
+/// The port servers listen on unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 7878;
+
+/// Appends `DEFAULT_PORT` to a server address entered by the user, unless it already
+/// specifies a port.
+///
+/// ```
+/// use chat_rs::with_default_port;
+///
+/// assert_eq!(with_default_port("example.com"), "example.com:7878");
+/// assert_eq!(with_default_port("127.0.0.1:9000"), "127.0.0.1:9000");
+/// assert_eq!(with_default_port("::1"), "[::1]:7878");
+/// ```
+pub fn with_default_port(address: &str) -> String {
+    if address.parse::<std::net::Ipv6Addr>().is_ok() {
+        return format!("[{}]:{}", address, DEFAULT_PORT);
+    }
+    match address.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => address.to_string(),
+        _ => format!("{}:{}", address, DEFAULT_PORT),
+    }
+}
 
-/// A struct representing a `TcpStream` belonging to a chat session.
+/// A struct representing a transport belonging to a chat session, a `TcpStream` by default.
 /// This struct contains methods useful for sending and receiving information
 /// using BCMP, and is highly recommended for working consistently between the
 /// server and the client.
-pub struct ChatStream {
-    pub inner: TcpStream,
-    cipher: Option<Aes256Gcm>, // 256-bit key
+///
+/// Any `AsyncRead + AsyncWrite` transport can be used, e.g. a Unix domain socket,
+/// or an in-memory pipe:
+/// ```
+/// use chat_rs::{ChatStream, Msg, ReceiveMsg, SendMsg, MSG_LENGTH};
+///
+/// #[tokio::main]
+/// async fn main() -> chat_rs::Result<()> {
+///     let (client, server) = tokio::io::duplex(MSG_LENGTH);
+///     let mut client = ChatStream::new(client);
+///     let mut server = ChatStream::new(server);
+///
+///     client.send_msg(&Msg::UserMsg("hello".into())).await?;
+///
+///     let mut buffer = [0u8; MSG_LENGTH];
+///     let msg = server.receive_msg(&mut buffer).await?;
+///     assert_eq!(msg.string(), "hello");
+///
+///     Ok(())
+/// }
+/// ```
+pub struct ChatStream<T = TcpStream> {
+    pub inner: T,
+    send_cipher: Option<SendCipher>,
+    receive_cipher: Option<ReceiveCipher>,
+    rekey_policy: RekeyPolicy,
+    framing: Framing,
+    /// The client's and then the server's hello, as sent on the wire, which the
+    /// server's identity signs along with the key exchange.
+    hellos: Vec<u8>,
+}
+
+/// Settings for how messages are put into frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framing {
+    /// The protocol version agreed on in the hello exchange. Messages the peer's
+    /// version doesn't know are sent as an older equivalent, or not at all.
+    pub version: u16,
+    /// Whether messages too big for a single frame may be sent as fragments.
+    /// This is enabled by the hello exchange when both sides support it.
+    pub fragmentation: bool,
+    /// The size limit for messages reassembled from fragments.
+    pub max_message_size: usize,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            version: PROTOCOL_VERSION,
+            fragmentation: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
 }
 
 #[async_trait]
 pub trait SendMsg {
     type Writer: AsyncWrite + Unpin + Send;
 
-    fn get_writer_cipher(&mut self) -> (&mut Self::Writer, Option<&Aes256Gcm>);
+    fn get_writer_cipher(&mut self) -> (&mut Self::Writer, Option<&mut SendCipher>);
+
+    fn framing(&self) -> Framing {
+        Framing::default()
+    }
 
     /// Send a message using the contained `TcpStream`, formatted according to
     /// BCMP, and returns a result which states if the operation was
@@ -66,24 +175,13 @@ pub trait SendMsg {
     /// }
     /// ```
     async fn send_msg(&mut self, msg: &Msg) -> Result<()> {
+        let framing = self.framing();
         let (writer, cipher) = self.get_writer_cipher();
 
-        let mut buffer = Vec::with_capacity(MSG_LENGTH);
-        buffer.extend(&msg.encode_header());
-        buffer.extend(msg.string().as_bytes());
-
-        if buffer.len() > MSG_LENGTH {
-            bail!("Attempted to send an invalid-length message (too big)");
-        }
-
-        if let Some(cipher) = cipher {
-            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-            cipher.encrypt_in_place(&nonce, &[], &mut buffer)?;
+        let mut wire = Vec::with_capacity(MSG_LENGTH);
+        encode_outgoing(msg, framing, cipher, &mut wire)?;
 
-            writer.write_u16(buffer.len() as u16).await?;
-            writer.write_all(&nonce).await?;
-        }
-        writer.write_all(&buffer).await?;
+        writer.write_all(&wire).await?;
         writer.flush().await?;
         Ok(())
     }
@@ -93,7 +191,11 @@ pub trait SendMsg {
 pub trait ReceiveMsg {
     type Reader: AsyncRead + Unpin + Send;
 
-    fn get_reader_cipher(&mut self) -> (&mut Self::Reader, Option<&Aes256Gcm>);
+    fn get_reader_cipher(&mut self) -> (&mut Self::Reader, Option<&mut ReceiveCipher>);
+
+    fn framing(&self) -> Framing {
+        Framing::default()
+    }
 
     /// Receive a BCMP formatted message, using the provided buffer
     /// as a means for memory efficiency. Buffer must be of length `MSG_LENGTH` at least.
@@ -118,161 +220,762 @@ pub trait ReceiveMsg {
     ///     Ok(())
     /// }
     /// ```
-    async fn receive_msg(&mut self, mut buffer: &mut [u8]) -> Result<Msg> {
-        let (reader, cipher) = self.get_reader_cipher();
-
-        if let Some(cipher) = cipher {
-            let clen = reader.read_u16().await? as usize;
+    async fn receive_msg(&mut self, buffer: &mut [u8]) -> Result<Msg> {
+        let framing = self.framing();
+        let (reader, mut cipher) = self.get_reader_cipher();
+        let mut fragments = Vec::new();
+
+        loop {
+            let (code, payload) = read_frame(reader, cipher.as_deref_mut(), buffer).await?;
+
+            if let Some(msg) = accept_frame(
+                code,
+                payload,
+                framing,
+                cipher.as_deref_mut(),
+                &mut fragments,
+            )? {
+                return Ok(msg);
+            }
+        }
+    }
+}
 
-            if clen > MSG_LENGTH {
-                bail!("Received invalid cyphertext length (too big)");
+/// Encodes `msg` along with any rekey messages that have to precede it, and appends
+/// the result to `wire`. Messages the peer's version doesn't know are encoded as
+/// `Msg::for_version` tells, which may be nothing at all.
+fn encode_outgoing(
+    msg: &Msg,
+    framing: Framing,
+    mut cipher: Option<&mut SendCipher>,
+    wire: &mut Vec<u8>,
+) -> Result<()> {
+    let older;
+    let msg = if msg.version() <= framing.version {
+        msg
+    } else {
+        match msg.clone().for_version(framing.version) {
+            Some(msg) => {
+                older = msg;
+                &older
             }
+            None => return Ok(()),
+        }
+    };
 
-            reader.read_exact(&mut buffer[..12]).await?;
-            let nonce;
-            (nonce, buffer) = buffer.split_at_mut(12);
-            let nonce = GenericArray::from_slice(nonce);
+    if let Some(cipher) = cipher.as_deref_mut() {
+        for step in cipher.rekey_steps() {
+            match step {
+                RekeyStep::Publish(public) => {
+                    encode_msg(&Msg::Rekey(public), framing, Some(cipher), wire)?
+                }
+                RekeyStep::Switch(key) => {
+                    let msg = Msg::Rekey(String::new());
+                    encode_msg(&msg, framing, Some(cipher), wire)?;
+                    cipher.switch(*key);
+                }
+            }
+        }
+    }
+    encode_msg(msg, framing, cipher, wire)
+}
 
-            reader.read_exact(&mut buffer[..clen]).await?;
+/// Encodes `msg` into as many frames as it takes, encrypting them if a cipher is given,
+/// and appends the result to `wire`.
+///
+/// Messages that don't fit in a single frame are encoded as the message code, followed
+/// by 4 (big endian) bytes of payload length and the payload, and that is split into
+/// fragment frames.
+fn encode_msg(
+    msg: &Msg,
+    framing: Framing,
+    mut cipher: Option<&mut SendCipher>,
+    wire: &mut Vec<u8>,
+) -> Result<()> {
+    let string = msg.string();
+    if string.len() + 3 <= MSG_LENGTH {
+        return encode_frame(msg.code(), string.as_bytes(), cipher, wire);
+    }
 
-            let plaintext = cipher.decrypt(nonce, &buffer[..clen])?;
-            buffer[..plaintext.len()].copy_from_slice(&plaintext);
+    if !framing.fragmentation {
+        return Err(ChatError::MessageTooLarge(string.len()));
+    }
+    let length =
+        u32::try_from(string.len()).map_err(|_| ChatError::MessageTooLarge(string.len()))?;
+
+    let mut message = Vec::with_capacity(5 + string.len());
+    message.push(msg.code());
+    message.extend(length.to_be_bytes());
+    message.extend(string.as_bytes());
+
+    let mut chunks = message.chunks(FRAGMENT_LENGTH).peekable();
+    while let Some(chunk) = chunks.next() {
+        let code = if chunks.peek().is_some() {
+            FRAGMENT_CODE
         } else {
-            reader.read_exact(&mut buffer[0..3]).await?;
+            LAST_FRAGMENT_CODE
         };
+        encode_frame(code, chunk, cipher.as_deref_mut(), wire)?;
+    }
+    Ok(())
+}
+
+/// Encodes a single frame, encrypting it if a cipher is given, and appends the result
+/// to `wire`.
+fn encode_frame(
+    code: u8,
+    payload: &[u8],
+    cipher: Option<&mut SendCipher>,
+    wire: &mut Vec<u8>,
+) -> Result<()> {
+    let mut buffer = Vec::with_capacity(MSG_LENGTH + TAG_SIZE);
+    buffer.push(code);
+    buffer.extend((payload.len() as u16).to_be_bytes());
+    buffer.extend(payload);
+
+    if buffer.len() > MSG_LENGTH {
+        return Err(ChatError::MessageTooLarge(payload.len()));
+    }
+
+    if let Some(cipher) = cipher {
+        let nonce = cipher.seal(&mut buffer)?;
 
-        let (code, length) = Msg::parse_header(&buffer[0..3]);
-        buffer = &mut buffer[3..];
+        wire.extend((buffer.len() as u16).to_be_bytes());
+        wire.extend(nonce);
+    }
+    wire.extend(buffer);
+    Ok(())
+}
 
-        if length + 3 > MSG_LENGTH {
-            bail!("Received invalid message length (too big)");
+/// Checks the fragments received so far against their declared length and the size
+/// limit, and returns whether they form a complete message.
+fn reassemble(fragments: &[u8], framing: Framing, is_last: bool) -> Result<bool> {
+    if fragments.len() < 5 {
+        if is_last {
+            return Err(ChatError::Protocol("incomplete fragmented message"));
         }
+        return Ok(false);
+    }
 
-        let string = if cipher.is_some() {
-            String::from_utf8_lossy(&buffer[..length]).to_string()
-        } else {
-            reader.read_exact(&mut buffer[..length]).await?;
-            String::from_utf8_lossy(&buffer[..length]).to_string()
-        };
+    let length = u32::from_be_bytes([fragments[1], fragments[2], fragments[3], fragments[4]]);
+    let length = length as usize;
+    if length > framing.max_message_size {
+        return Err(ChatError::MessageTooLarge(length));
+    }
+
+    match (fragments.len() - 5).cmp(&length) {
+        std::cmp::Ordering::Greater => Err(ChatError::Protocol("more fragments than declared")),
+        std::cmp::Ordering::Equal if is_last => Ok(true),
+        std::cmp::Ordering::Less if !is_last => Ok(false),
+        _ => Err(ChatError::Protocol("incomplete fragmented message")),
+    }
+}
 
-        match Msg::from_parts(code, string) {
-            Some(msg) => Ok(msg),
-            None => Err(anyhow!("Received invalid message code")),
+/// Handles a single received frame, and returns the message it completes, if any.
+/// Fragments are collected in `fragments` until the last one arrives, and rekeys are
+/// handled here and never returned. Messages with an unknown code are skipped, since
+/// they come from a newer peer that should only send them when it's safe to ignore
+/// them.
+fn accept_frame(
+    code: u8,
+    payload: &[u8],
+    framing: Framing,
+    cipher: Option<&mut ReceiveCipher>,
+    fragments: &mut Vec<u8>,
+) -> Result<Option<Msg>> {
+    let msg = match code {
+        FRAGMENT_CODE | LAST_FRAGMENT_CODE => {
+            fragments.extend_from_slice(payload);
+            if !reassemble(fragments, framing, code == LAST_FRAGMENT_CODE)? {
+                return Ok(None);
+            }
+            let string = decode_utf8(&fragments[5..]);
+            let msg = string.and_then(|string| Msg::from_parts(fragments[0], string));
+            fragments.clear();
+            msg
+        }
+        _ if !fragments.is_empty() => {
+            return Err(ChatError::Protocol(
+                "message in the middle of a fragmented one",
+            ))
+        }
+        _ => decode_utf8(payload).and_then(|string| Msg::from_parts(code, string)),
+    };
+    let msg = match msg {
+        Err(ChatError::UnknownCode(_)) => return Ok(None),
+        msg => msg?,
+    };
+
+    if let Msg::Rekey(public) = msg {
+        match cipher {
+            Some(cipher) => cipher.handle_rekey(&public)?,
+            None => return Err(ChatError::Protocol("rekey on an unencrypted stream")),
         }
+        return Ok(None);
     }
+
+    Ok(Some(msg))
+}
+
+fn decode_utf8(payload: &[u8]) -> Result<String> {
+    String::from_utf8(payload.to_vec()).map_err(|_| ChatError::InvalidUtf8)
+}
+
+/// Reads a single frame into `buffer`, decrypting it if a cipher is given, and returns
+/// its code and payload.
+async fn read_frame<'a, R: AsyncRead + Unpin + Send>(
+    reader: &mut R,
+    cipher: Option<&mut ReceiveCipher>,
+    buffer: &'a mut [u8],
+) -> Result<(u8, &'a [u8])> {
+    if let Some(cipher) = cipher {
+        let clen = reader.read_u16().await? as usize;
+
+        if clen > MSG_LENGTH + TAG_SIZE {
+            return Err(ChatError::FrameTooLarge(clen));
+        }
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        reader.read_exact(&mut nonce).await?;
+
+        let mut plaintext = vec![0u8; clen];
+        reader.read_exact(&mut plaintext).await?;
+
+        cipher.open(&nonce, &mut plaintext)?;
+        if plaintext.len() > buffer.len() {
+            return Err(ChatError::FrameTooLarge(plaintext.len()));
+        }
+        buffer[..plaintext.len()].copy_from_slice(&plaintext);
+        return split_frame(&buffer[..plaintext.len()]);
+    }
+
+    reader.read_exact(&mut buffer[0..3]).await?;
+    let (_, length) = Msg::parse_header(&buffer[0..3]);
+
+    if length + 3 > MSG_LENGTH {
+        return Err(ChatError::FrameTooLarge(length + 3));
+    }
+
+    reader.read_exact(&mut buffer[3..3 + length]).await?;
+    split_frame(&buffer[..3 + length])
 }
 
-impl ChatStream {
-    /// Generate a new ChatStream from an existing TcpStream, without encryption (Use ChatStream::encrypt
-    /// to add a key).
-    pub fn new(stream: TcpStream) -> Self {
+/// Splits a complete (decrypted) frame into its code and payload, checking that its
+/// header matches its actual length.
+fn split_frame(frame: &[u8]) -> Result<(u8, &[u8])> {
+    if frame.len() < 3 {
+        return Err(ChatError::Protocol("truncated frame"));
+    }
+    let (code, length) = Msg::parse_header(&frame[0..3]);
+
+    if length + 3 > MSG_LENGTH {
+        return Err(ChatError::FrameTooLarge(length + 3));
+    }
+    if length + 3 != frame.len() {
+        return Err(ChatError::Protocol("frame with an inconsistent length"));
+    }
+
+    Ok((code, &frame[3..]))
+}
+
+/// An optional protocol feature, advertised by both sides during the hello exchange.
+/// Capabilities are sent by name, and names that aren't recognized are ignored,
+/// so that newer peers can advertise features older ones don't know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    Encryption,
+    Fragmentation,
+    /// Chat messages are sent as `StampedUserMsg` and `StampedHistoryMsg`, which carry a
+    /// `Stamp`, instead of `NickedUserMsg` and `HistoryMsg`.
+    Timestamps,
+}
+
+impl Capability {
+    /// Returns the name of the capability, as sent on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Encryption => "encryption",
+            Capability::Fragmentation => "fragmentation",
+            Capability::Timestamps => "timestamps",
+        }
+    }
+
+    /// Parses a capability name, returning `None` for unknown capabilities.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "encryption" => Some(Capability::Encryption),
+            "fragmentation" => Some(Capability::Fragmentation),
+            "timestamps" => Some(Capability::Timestamps),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of a successful hello exchange: the protocol version both sides
+/// agreed to speak, and the capabilities supported by both of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u16,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Handshake {
+    /// Checks whether both sides agreed on the given capability.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+fn common_capabilities(
+    ours: &[Capability],
+    theirs: &[Capability],
+    version: u16,
+) -> Vec<Capability> {
+    let mut common: Vec<Capability> = ours
+        .iter()
+        .filter(|cap| theirs.contains(cap))
+        .filter(|cap| **cap != Capability::Encryption || version >= SIGNED_HELLO_VERSION)
+        .copied()
+        .collect();
+    common.sort();
+    common.dedup();
+    common
+}
+
+impl<T> ChatStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    /// Generate a new ChatStream from an existing transport, without encryption (Use
+    /// ChatStream::encrypt_server or ChatStream::encrypt_client to add a key).
+    pub fn new(stream: T) -> Self {
         ChatStream {
             inner: stream,
-            cipher: None,
+            send_cipher: None,
+            receive_cipher: None,
+            rekey_policy: RekeyPolicy::default(),
+            framing: Framing::default(),
+            hellos: Vec::new(),
         }
     }
 
-    /// Encrypts the current ChatStream.
-    /// NOTE: This operation must be executed on both ends to work.
+    /// Encrypts the current ChatStream as the server, authenticating the key
+    /// exchange with the server's long-term `identity`.
+    /// NOTE: The client must call `ChatStream::encrypt_client` at the same time.
     ///
     /// Calling this function when the stream is already encrypted
     /// will do nothing.
-    pub async fn encrypt(&mut self) -> Result<()> {
-        if self.cipher.is_some() {
+    pub async fn encrypt_server(&mut self, identity: &Identity) -> Result<()> {
+        if self.send_cipher.is_some() {
             return Ok(());
         }
         let my_secret = EphemeralSecret::random(&mut OsRng);
-        let my_public = EncodedPoint::from(&my_secret.public_key());
+        let (my_public, other_public) = self.exchange_public(&my_secret).await?;
+
+        // The signature covers the hellos and both ephemeral keys, binding it to
+        // this session and to what was negotiated in it.
+        let transcript = self.transcript(&other_public, &my_public);
+        let signature = identity.sign(&transcript).to_bytes();
+        self.inner
+            .write_all(&identity::encode_public(&identity.public_key()))
+            .await?;
+        self.inner.write_all(&signature).await?;
+        self.inner.flush().await?;
+
+        self.derive_ciphers(&my_secret, &other_public, Direction::ServerToClient)
+    }
+
+    /// Encrypts the current ChatStream as the client, and returns the server's
+    /// long-term identity key once its signature over the exchange has been verified.
+    /// The caller is responsible for checking that the key belongs to the server,
+    /// e.g. using `KnownHosts`. Servers older than `SIGNED_HELLO_VERSION` are refused.
+    /// NOTE: The server must call `ChatStream::encrypt_server` at the same time.
+    pub async fn encrypt_client(&mut self) -> Result<VerifyingKey> {
+        if self.send_cipher.is_some() {
+            return Err(ChatError::BadHandshake(
+                "the stream is already encrypted".into(),
+            ));
+        }
+        if self.framing.version < SIGNED_HELLO_VERSION {
+            return Err(ChatError::BadHandshake(format!(
+                "server speaks BCMP version {}, which is too old to encrypt securely",
+                self.framing.version
+            )));
+        }
+        let my_secret = EphemeralSecret::random(&mut OsRng);
+        let (my_public, other_public) = self.exchange_public(&my_secret).await?;
 
-        let public_bytes = my_public.as_bytes(); // The length of this should be exactly ECDH_PUBLIC_LEN bytes
-        self.inner.write_all(public_bytes).await?;
+        let mut identity_public = [0u8; IDENTITY_PUBLIC_LEN];
+        let mut signature = [0u8; SIGNATURE_LEN];
+        self.inner.read_exact(&mut identity_public).await?;
+        self.inner.read_exact(&mut signature).await?;
+
+        let transcript = self.transcript(&my_public, &other_public);
+        let server_key = identity::verify(&identity_public, &transcript, &signature)?;
+
+        self.derive_ciphers(&my_secret, &other_public, Direction::ClientToServer)?;
+        Ok(server_key)
+    }
+
+    /// Sends our ephemeral public key and receives the other side's, returning
+    /// both in their encoded form.
+    async fn exchange_public(
+        &mut self,
+        my_secret: &EphemeralSecret,
+    ) -> Result<([u8; ECDH_PUBLIC_LEN], [u8; ECDH_PUBLIC_LEN])> {
+        let mut my_public = [0u8; ECDH_PUBLIC_LEN];
+        my_public.copy_from_slice(EncodedPoint::from(&my_secret.public_key()).as_bytes());
+        self.inner.write_all(&my_public).await?;
         self.inner.flush().await?;
 
-        let mut other_public_bytes = [0u8; ECDH_PUBLIC_LEN];
-        self.inner.read_exact(&mut other_public_bytes).await?;
-        let other_public = PublicKey::from_sec1_bytes(&other_public_bytes)?;
+        let mut other_public = [0u8; ECDH_PUBLIC_LEN];
+        self.inner.read_exact(&mut other_public).await?;
+        Ok((my_public, other_public))
+    }
+
+    fn transcript(&self, client_public: &[u8], server_public: &[u8]) -> Vec<u8> {
+        let mut transcript = b"chat-rs identity".to_vec();
+        transcript.extend(&self.hellos);
+        transcript.extend(client_public);
+        transcript.extend(server_public);
+        transcript
+    }
+
+    /// Derives the session keys, and sets up the ciphers for sending in `outgoing` and
+    /// receiving in the opposite direction.
+    fn derive_ciphers(
+        &mut self,
+        my_secret: &EphemeralSecret,
+        other_public: &[u8],
+        outgoing: Direction,
+    ) -> Result<()> {
+        let other_public = PublicKey::from_sec1_bytes(other_public)
+            .map_err(|_| ChatError::BadHandshake("invalid ECDH public key".into()))?;
+
+        let (send, receive) =
+            cipher::new_session(my_secret, &other_public, outgoing, self.rekey_policy);
+        self.send_cipher = Some(send);
+        self.receive_cipher = Some(receive);
+        Ok(())
+    }
+
+    /// Sets how much the session keys may be used for before they're replaced
+    /// with a fresh ECDH. Applies to the current session if the stream is
+    /// already encrypted, and to the halves returned by `ChatStream::into_split`.
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.rekey_policy = policy;
+        if let Some(cipher) = &mut self.send_cipher {
+            cipher.set_rekey_policy(policy);
+        }
+    }
 
-        let shared = my_secret.diffie_hellman(&other_public);
-        let hk = shared.extract::<Sha256>(None);
+    /// Performs the client side of the hello exchange, which must be the first thing
+    /// sent on a new connection. The client advertises `PROTOCOL_VERSION` along with
+    /// the given capabilities, and returns what the server agreed to.
+    pub async fn client_hello(&mut self, capabilities: &[Capability]) -> Result<Handshake> {
+        self.send_hello(&Msg::Hello(PROTOCOL_VERSION, capabilities.to_vec()))
+            .await?;
+
+        match self.receive_hello().await? {
+            Msg::Hello(version, theirs) => {
+                if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+                    return Err(ChatError::BadHandshake(format!(
+                        "server speaks BCMP version {}, which isn't supported (expected {}-{})",
+                        version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )));
+                }
+                let handshake = Handshake {
+                    version,
+                    capabilities: common_capabilities(capabilities, &theirs, version),
+                };
+                self.framing.version = handshake.version;
+                self.framing.fragmentation = handshake.supports(Capability::Fragmentation);
+                Ok(handshake)
+            }
+            Msg::ConnectionRejected(reason) => Err(ChatError::Rejected(reason)),
+            msg => Err(ChatError::BadHandshake(format!(
+                "expected a hello from the server, got code {}",
+                msg.code()
+            ))),
+        }
+    }
+
+    /// Performs the server side of the hello exchange. The client's hello is
+    /// checked against the supported protocol versions and the `required`
+    /// capabilities; on a mismatch, the client is sent a `ConnectionRejected`
+    /// explaining why, and an error with the same reason is returned.
+    pub async fn server_hello(
+        &mut self,
+        capabilities: &[Capability],
+        required: &[Capability],
+    ) -> Result<Handshake> {
+        let (version, theirs) = match self.receive_hello().await? {
+            Msg::Hello(version, theirs) => (version, theirs),
+            _ => {
+                return self
+                    .reject_hello("outdated client: a protocol hello is required".into())
+                    .await
+            }
+        };
+
+        if version < MIN_PROTOCOL_VERSION {
+            return self
+                .reject_hello(format!(
+                    "unsupported protocol version {} (server supports {}-{})",
+                    version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ))
+                .await;
+        }
+
+        let handshake = Handshake {
+            version: version.min(PROTOCOL_VERSION),
+            capabilities: common_capabilities(capabilities, &theirs, version),
+        };
+
+        if let Some(missing) = required.iter().find(|cap| !handshake.supports(**cap)) {
+            let reason = if theirs.contains(missing) {
+                format!(
+                    "client is too old for {} (version {} or newer is required)",
+                    missing.name(),
+                    SIGNED_HELLO_VERSION
+                )
+            } else {
+                format!("client does not support {}", missing.name())
+            };
+            return self.reject_hello(reason).await;
+        }
+
+        self.send_hello(&Msg::Hello(
+            handshake.version,
+            handshake.capabilities.clone(),
+        ))
+        .await?;
+
+        self.framing.version = handshake.version;
+        self.framing.fragmentation = handshake.supports(Capability::Fragmentation);
+        Ok(handshake)
+    }
 
-        let mut key = [0u8; 32];
-        hk.expand(&[], &mut key)
-            .expect("hk.expand got invalid length - this should never ever happen!");
+    /// Sends a hello, keeping a copy of it for the key exchange's transcript.
+    async fn send_hello(&mut self, msg: &Msg) -> Result<()> {
+        let mut wire = Vec::with_capacity(MSG_LENGTH);
+        encode_outgoing(msg, self.framing, None, &mut wire)?;
+        self.hellos.extend(&wire);
 
-        let key = GenericArray::from_slice(&key);
-        self.cipher = Some(Aes256Gcm::new(key));
+        self.inner.write_all(&wire).await?;
+        self.inner.flush().await?;
         Ok(())
     }
 
-    /// Convenience method for `TcpStream::peer_addr()`
-    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.inner.peer_addr()
+    /// Receives a hello (or whatever the other side sent instead), keeping a copy of
+    /// the frame exactly as it arrived for the key exchange's transcript.
+    async fn receive_hello(&mut self) -> Result<Msg> {
+        let mut buffer = [0u8; MSG_LENGTH];
+        let (code, payload) = read_frame(&mut self.inner, None, &mut buffer).await?;
+        self.hellos.push(code);
+        self.hellos.extend((payload.len() as u16).to_be_bytes());
+        self.hellos.extend(payload);
+
+        decode_utf8(payload).and_then(|string| Msg::from_parts(code, string))
+    }
+
+    async fn reject_hello<R>(&mut self, reason: String) -> Result<R> {
+        self.send_msg(&Msg::ConnectionRejected(reason.clone()))
+            .await
+            .unwrap_or(()); // the connection is dropped either way
+        Err(ChatError::BadHandshake(reason))
+    }
+
+    /// Returns the protocol version agreed on in the hello exchange, or
+    /// `PROTOCOL_VERSION` before it.
+    pub fn version(&self) -> u16 {
+        self.framing.version
+    }
+
+    /// Sets the size limit for messages reassembled from fragments. Receiving a bigger
+    /// message is an error.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.framing.max_message_size = size;
     }
 
     /// Splits the current stream into a reading and writing half,
-    /// using TcpStream::into_split
-    pub fn into_split(self) -> (ChatReaderHalf, ChatWriterHalf) {
-        let (read, write) = self.inner.into_split();
+    /// using tokio::io::split. Each half keeps the cipher state
+    /// (and sequence numbers) for its own direction.
+    pub fn into_split(self) -> (ChatReaderHalf<T>, ChatWriterHalf<T>) {
+        let (read, write) = tokio::io::split(self.inner);
 
         let reader = ChatReaderHalf {
             inner: read,
-            cipher: self.cipher.clone(),
+            cipher: self.receive_cipher,
+            framing: self.framing,
         };
 
         let writer = ChatWriterHalf {
             inner: write,
-            cipher: self.cipher,
+            cipher: self.send_cipher,
+            framing: self.framing,
         };
 
         (reader, writer)
     }
+
+    /// Turns the stream into a `Framed` transport, which is a `Stream` of received
+    /// messages and a `Sink` of messages to send. This should be done after the hello
+    /// exchange and `encrypt_*`, whose state carries over to the codec.
+    ///
+    /// ```
+    /// use chat_rs::{ChatStream, Msg, MSG_LENGTH};
+    /// use futures::{SinkExt, StreamExt};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> chat_rs::Result<()> {
+    ///     let (client, server) = tokio::io::duplex(MSG_LENGTH);
+    ///     let mut client = ChatStream::new(client).into_framed();
+    ///     let mut server = ChatStream::new(server).into_framed();
+    ///
+    ///     client.send(Msg::UserMsg("hello".into())).await?;
+    ///
+    ///     let msg = server.next().await.expect("stream ended")?;
+    ///     assert_eq!(msg.string(), "hello");
+    ///
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn into_framed(self) -> Framed<T, BcmpCodec> {
+        let codec = BcmpCodec::with_state(self.send_cipher, self.receive_cipher, self.framing);
+        Framed::new(self.inner, codec)
+    }
+}
+
+impl ChatStream<TcpStream> {
+    /// Convenience method for `TcpStream::peer_addr()`
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
 }
 
-impl SendMsg for ChatStream {
-    type Writer = TcpStream;
+impl<T> SendMsg for ChatStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Writer = T;
+
+    fn get_writer_cipher(&mut self) -> (&mut Self::Writer, Option<&mut SendCipher>) {
+        (&mut self.inner, self.send_cipher.as_mut())
+    }
 
-    fn get_writer_cipher(&mut self) -> (&mut Self::Writer, Option<&Aes256Gcm>) {
-        (&mut self.inner, self.cipher.as_ref())
+    fn framing(&self) -> Framing {
+        self.framing
     }
 }
 
-impl ReceiveMsg for ChatStream {
-    type Reader = TcpStream;
+impl<T> ReceiveMsg for ChatStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Reader = T;
 
-    fn get_reader_cipher(&mut self) -> (&mut Self::Reader, Option<&Aes256Gcm>) {
-        (&mut self.inner, self.cipher.as_ref())
+    fn get_reader_cipher(&mut self) -> (&mut Self::Reader, Option<&mut ReceiveCipher>) {
+        (&mut self.inner, self.receive_cipher.as_mut())
+    }
+
+    fn framing(&self) -> Framing {
+        self.framing
     }
 }
 
-impl std::fmt::Debug for ChatStream {
+impl<T> std::fmt::Debug for ChatStream<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "ChatStream")
     }
 }
 
-pub struct ChatReaderHalf {
-    inner: OwnedReadHalf,
-    cipher: Option<Aes256Gcm>,
+pub struct ChatReaderHalf<T = TcpStream> {
+    inner: ReadHalf<T>,
+    cipher: Option<ReceiveCipher>,
+    framing: Framing,
 }
 
-impl ReceiveMsg for ChatReaderHalf {
-    type Reader = OwnedReadHalf;
+impl<T> ReceiveMsg for ChatReaderHalf<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Reader = ReadHalf<T>;
+
+    fn get_reader_cipher(&mut self) -> (&mut Self::Reader, Option<&mut ReceiveCipher>) {
+        (&mut self.inner, self.cipher.as_mut())
+    }
+
+    fn framing(&self) -> Framing {
+        self.framing
+    }
+}
 
-    fn get_reader_cipher(&mut self) -> (&mut Self::Reader, Option<&Aes256Gcm>) {
-        (&mut self.inner, self.cipher.as_ref())
+impl<T> ChatReaderHalf<T> {
+    /// Turns the half into a `Stream` of received messages. See `ChatStream::into_framed`.
+    pub fn into_framed(self) -> FramedReader<T> {
+        let codec = BcmpCodec::with_state(None, self.cipher, self.framing);
+        FramedRead::new(self.inner, codec)
     }
 }
 
-pub struct ChatWriterHalf {
-    inner: OwnedWriteHalf,
-    cipher: Option<Aes256Gcm>,
+pub struct ChatWriterHalf<T = TcpStream> {
+    inner: WriteHalf<T>,
+    cipher: Option<SendCipher>,
+    framing: Framing,
 }
 
-impl SendMsg for ChatWriterHalf {
-    type Writer = OwnedWriteHalf;
+impl<T> SendMsg for ChatWriterHalf<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type Writer = WriteHalf<T>;
+
+    fn get_writer_cipher(&mut self) -> (&mut Self::Writer, Option<&mut SendCipher>) {
+        (&mut self.inner, self.cipher.as_mut())
+    }
+
+    fn framing(&self) -> Framing {
+        self.framing
+    }
+}
 
-    fn get_writer_cipher(&mut self) -> (&mut Self::Writer, Option<&Aes256Gcm>) {
-        (&mut self.inner, self.cipher.as_ref())
+impl<T> ChatWriterHalf<T> {
+    /// Turns the half into a `Sink` of messages to send. See `ChatStream::into_framed`.
+    pub fn into_framed(self) -> FramedWriter<T> {
+        let codec = BcmpCodec::with_state(self.cipher, None, self.framing);
+        FramedWrite::new(self.inner, codec)
+    }
+}
+
+/// The identity the server gives a chat message when relaying it: an ID, which is
+/// unique and increases with every message, and the time the message was sent at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Stamp {
+    pub id: u64,
+    pub time: SystemTime,
+}
+
+impl Stamp {
+    fn encode(&self) -> String {
+        // times before the epoch can't be sent, and are clamped to it
+        let millis = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!("{}\0{}", self.id, millis)
+    }
+
+    /// Parses a stamp off the start of `string`, returning it and the rest.
+    fn decode(string: String) -> Option<(Self, String)> {
+        let (id, rest) = Msg::nicked_split(string)?;
+        let (millis, rest) = Msg::nicked_split(rest)?;
+        let stamp = Stamp {
+            id: id.parse().ok()?,
+            time: UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?),
+        };
+        Some((stamp, rest))
     }
 }
 
@@ -280,17 +983,93 @@ impl SendMsg for ChatWriterHalf {
 #[derive(Debug, Clone)]
 pub enum Msg {
     UserMsg(String),
-    NickedUserMsg(String, String),
+    NickedUserMsg(Nick, String),
+    /// A chat message that was sent before the client joined its room, replayed along
+    /// with the time it was sent at.
+    HistoryMsg(Nick, SystemTime, String),
+    /// A `NickedUserMsg` with its `Stamp`, for clients with `Capability::Timestamps`.
+    StampedUserMsg(Stamp, Nick, String),
+    /// A `HistoryMsg` with its `Stamp`, for clients with `Capability::Timestamps`.
+    StampedHistoryMsg(Stamp, Nick, String),
 
     NickChange(String),
-    NickedNickChange(String, String),
+    NickedNickChange(Nick, Nick),
+
+    /// Logs in to the account of the nick the client connected with, or as a guest with
+    /// an empty password. Sent right after the connection is accepted (and encrypted).
+    Authenticate(String),
+    /// Registers the sender's nick as an account with the given password.
+    Register(String),
+    /// The server accepted an `Authenticate`.
+    Authenticated,
 
-    NickedConnect(String),
-    NickedDisconnect(String),
+    NickedConnect(Nick),
+    NickedDisconnect(Nick),
 
     Command(String),
-    NickedCommand(String, String),
+    /// A private message to a single user, sent by a client.
+    PrivateMsg(Nick, String),
+    NickedPrivateMsg(Nick, String),
+    NickedAction(Nick, String),
+
+    /// Moves the sending client into a room, leaving the one it was in.
+    JoinRoom(Room),
+    /// Moves the sending client back into the lobby.
+    PartRoom,
+    ListRooms,
+    NickedJoin(Nick, Room),
+    NickedPart(Nick, Room),
+    /// Everyone in the client's room, sent when it connects and whenever it joins
+    /// another room.
+    Roster(Room, Vec<Nick>),
+
+    /// A request from the client was refused, for the given reason. It's new in
+    /// protocol version 6, so it isn't sent to peers that agreed on an older one.
+    ///
+    /// ```
+    /// use chat_rs::Msg;
+    ///
+    /// let error = Msg::Error("nick bob is taken".into());
+    /// assert_eq!(error.version(), 6);
+    /// assert!(error.for_version(5).is_none());
+    /// ```
+    Error(String),
+    ServerReply(String),
+    /// A warning from the server that wasn't asked for, e.g. about sending too fast.
+    Notice(String),
+    /// The server is closing the connection, for the given reason. Peers older than
+    /// version 10 are told the reason as a `ServerReply` instead, as long as they have it.
+    ///
+    /// ```
+    /// use chat_rs::Msg;
+    ///
+    /// let closed = Msg::ConnectionClosed("too slow".into());
+    /// let older = closed.clone().for_version(9);
+    /// assert!(matches!(older, Some(Msg::ServerReply(s)) if s == "disconnected: too slow"));
+    /// assert!(closed.for_version(5).is_none());
+    /// ```
+    ConnectionClosed(String),
+    /// The server is shutting down and about to close the connection, for the given
+    /// reason if it isn't empty.
+    ServerShutdown(String),
+    /// The server is closing the connection for good, e.g. because an operator kicked or
+    /// banned the user, for the given reason. Unlike after a `ConnectionClosed`, clients
+    /// shouldn't reconnect. Older peers are sent a `ConnectionClosed` instead.
+    ///
+    /// ```
+    /// use chat_rs::Msg;
+    ///
+    /// let kicked = Msg::Kicked("kicked by alice".into());
+    /// assert!(matches!(kicked.for_version(15), Some(Msg::ConnectionClosed(s)) if s == "kicked by alice"));
+    /// ```
+    Kicked(String),
+
+    /// Checks that the peer is still there. It must answer with a `Pong`.
+    Ping,
+    Pong,
 
+    Hello(u16, Vec<Capability>),
+    Rekey(String),
     ConnectionEncrypted,
     ConnectionAccepted,
     ConnectionRejected(String),
@@ -299,60 +1078,274 @@ pub enum Msg {
 impl Msg {
     /// Returns the numeral code of the message type.
     pub fn code(&self) -> u8 {
-        // if you change this, CHANGE FROM_PARTS AND STRING TOO!!
+        // if you change this, CHANGE FROM_PARTS, STRING AND VERSION TOO!!
         use Msg::*;
         match self {
             UserMsg(_) => 0,
             NickedUserMsg(_, _) => 100,
+            StampedUserMsg(_, _, _) => 106,
+            HistoryMsg(_, _, _) => 107,
+            StampedHistoryMsg(_, _, _) => 108,
 
             NickChange(_) => 1,
             NickedNickChange(_, _) => 101,
 
+            Authenticate(_) => 8,
+            Register(_) => 9,
+            Authenticated => 248,
+
             NickedConnect(_) => 98,
             NickedDisconnect(_) => 99,
 
             Command(_) => 3,
-            NickedCommand(_, _) => 103,
-
+            PrivateMsg(_, _) => 4,
+            NickedPrivateMsg(_, _) => 104,
+            NickedAction(_, _) => 105,
+
+            JoinRoom(_) => 5,
+            PartRoom => 6,
+            ListRooms => 7,
+            NickedJoin(_, _) => 110,
+            NickedPart(_, _) => 111,
+            Roster(_, _) => 112,
+
+            Error(_) => 200,
+            ServerReply(_) => 201,
+            Notice(_) => 202,
+            ConnectionClosed(_) => 203,
+            ServerShutdown(_) => 204,
+            Kicked(_) => 205,
+
+            Ping => 240,
+            Pong => 241,
+
+            Rekey(_) => 249,
+            Hello(_, _) => 252,
             ConnectionEncrypted => 253,
             ConnectionAccepted => 254,
             ConnectionRejected(_) => 255,
         }
     }
 
+    /// Returns the protocol version that introduced the message. Peers that agreed on
+    /// an older version don't know it, and are sent what `Msg::for_version` turns it
+    /// into instead.
+    pub fn version(&self) -> u16 {
+        use Msg::*;
+        match self {
+            UserMsg(_) | NickedUserMsg(_, _) | NickChange(_) | NickedNickChange(_, _) => 1,
+            NickedConnect(_) | NickedDisconnect(_) | Command(_) => 1,
+            Hello(_, _) | ConnectionEncrypted | ConnectionAccepted | ConnectionRejected(_) => 1,
+            Rekey(_) => 4,
+            Error(_) | ServerReply(_) | NickedPrivateMsg(_, _) | NickedAction(_, _) => 6,
+            PrivateMsg(_, _) => 7,
+            JoinRoom(_) | PartRoom | ListRooms | NickedJoin(_, _) | NickedPart(_, _) => 8,
+            // stamped messages are only sent with `Capability::Timestamps` anyway
+            HistoryMsg(_, _, _) | StampedUserMsg(_, _, _) | StampedHistoryMsg(_, _, _) => 9,
+            ConnectionClosed(_) => 10,
+            Ping | Pong => 11,
+            Authenticate(_) | Register(_) | Authenticated => 12,
+            Notice(_) => 13,
+            ServerShutdown(_) => 14,
+            Kicked(_) => 16,
+            Roster(_, _) => 15,
+        }
+    }
+
+    /// Turns the message into what a peer speaking the given protocol `version`
+    /// understands: messages that are newer than it are replaced by an older
+    /// equivalent, or dropped if there is none.
+    ///
+    /// ```
+    /// use chat_rs::{Msg, Room};
+    ///
+    /// let join = Msg::JoinRoom(Room::new("dev")?);
+    /// assert!(matches!(join.clone().for_version(8), Some(Msg::JoinRoom(_))));
+    /// assert!(matches!(join.clone().for_version(6), Some(Msg::Command(s)) if s == "join dev"));
+    /// assert!(join.for_version(5).is_none());
+    /// # Ok::<(), chat_rs::ChatError>(())
+    /// ```
+    pub fn for_version(self, version: u16) -> Option<Msg> {
+        if self.version() <= version {
+            return Some(self);
+        }
+        // servers run commands since version 6, and relay them as chat before it
+        let commands = version >= 6;
+        let older = match self {
+            Msg::HistoryMsg(nick, _, text) => Some(Msg::NickedUserMsg(nick, text)),
+            Msg::PrivateMsg(to, text) if commands => {
+                Some(Msg::Command(format!("msg {} {}", to, text)))
+            }
+            Msg::JoinRoom(room) if commands => Some(Msg::Command(format!("join {}", room))),
+            Msg::PartRoom if commands => Some(Msg::Command("part".into())),
+            Msg::ListRooms if commands => Some(Msg::Command("rooms".into())),
+            Msg::ConnectionClosed(reason) => {
+                Some(Msg::ServerReply(format!("disconnected: {}", reason)))
+            }
+            Msg::ServerShutdown(reason) => {
+                let mut closed = "the server is shutting down".to_string();
+                if !reason.is_empty() {
+                    closed += &format!(": {}", reason);
+                }
+                Some(Msg::ConnectionClosed(closed))
+            }
+            Msg::Kicked(reason) => Some(Msg::ConnectionClosed(reason)),
+            _ => None,
+        };
+        older?.for_version(version)
+    }
+
     /// Constructs a new Msg from a code and a string.
     /// Msg's that don't have a string will ignore the passed string.
-    pub fn from_parts(code: u8, string: String) -> Option<Self> {
+    pub fn from_parts(code: u8, string: String) -> Result<Self> {
         use Msg::*;
-        match code {
-            0 => Some(UserMsg(string)),
-            1 => Some(NickChange(string)),
-            98 => Some(NickedConnect(string)),
-            99 => Some(NickedDisconnect(string)),
-            3 => Some(Command(string)),
-            253 => Some(ConnectionEncrypted),
-            254 => Some(ConnectionAccepted),
-            255 => Some(ConnectionRejected(string)),
-            _ => {
-                let (a, b) = match Self::nicked_split(string) {
-                    Some((a, b)) => (a, b),
-                    None => return None,
-                };
+        let msg = match code {
+            0 => UserMsg(string),
+            1 => NickChange(string),
+            98 => NickedConnect(Self::parse_nick(code, string)?),
+            99 => NickedDisconnect(Self::parse_nick(code, string)?),
+            3 => Command(string),
+            5 => JoinRoom(Self::parse_room(code, string)?),
+            6 => PartRoom,
+            7 => ListRooms,
+            8 => Authenticate(string),
+            9 => Register(string),
+            112 => Self::parse_roster(string).ok_or(ChatError::MalformedPayload(code))?,
+            200 => Error(string),
+            201 => ServerReply(string),
+            202 => Notice(string),
+            203 => ConnectionClosed(string),
+            204 => ServerShutdown(string),
+            205 => Kicked(string),
+            240 => Ping,
+            241 => Pong,
+            248 => Authenticated,
+            249 => Rekey(string),
+            252 => Self::parse_hello(string).ok_or(ChatError::MalformedPayload(code))?,
+            253 => ConnectionEncrypted,
+            254 => ConnectionAccepted,
+            255 => ConnectionRejected(string),
+            4 | 100 | 101 | 104 | 105 | 106 | 107 | 108 | 110 | 111 => {
+                let (nick, other) =
+                    Self::nicked_split(string).ok_or(ChatError::MalformedPayload(code))?;
+                let nick = Self::parse_nick(code, nick)?;
                 match code {
-                    100 => Some(NickedUserMsg(a, b)),
-                    101 => Some(NickedNickChange(a, b)),
-                    103 => Some(NickedCommand(a, b)),
-                    _ => None,
+                    4 => PrivateMsg(nick, other),
+                    100 => NickedUserMsg(nick, other),
+                    101 => NickedNickChange(nick, Self::parse_nick(code, other)?),
+                    104 => NickedPrivateMsg(nick, other),
+                    105 => NickedAction(nick, other),
+                    107 => {
+                        let (time, text) =
+                            Self::nicked_split(other).ok_or(ChatError::MalformedPayload(code))?;
+                        let secs = time
+                            .parse()
+                            .map_err(|_| ChatError::MalformedPayload(code))?;
+                        HistoryMsg(nick, UNIX_EPOCH + Duration::from_secs(secs), text)
+                    }
+                    106 | 108 => {
+                        let (stamp, text) =
+                            Stamp::decode(other).ok_or(ChatError::MalformedPayload(code))?;
+                        if code == 106 {
+                            StampedUserMsg(stamp, nick, text)
+                        } else {
+                            StampedHistoryMsg(stamp, nick, text)
+                        }
+                    }
+                    110 => NickedJoin(nick, Self::parse_room(code, other)?),
+                    _ => NickedPart(nick, Self::parse_room(code, other)?),
+                }
+            }
+            _ => return Err(ChatError::UnknownCode(code)),
+        };
+        Ok(msg)
+    }
+
+    /// Returns the stamp of a stamped chat message.
+    pub fn stamp(&self) -> Option<&Stamp> {
+        match self {
+            Msg::StampedUserMsg(stamp, _, _) | Msg::StampedHistoryMsg(stamp, _, _) => Some(stamp),
+            _ => None,
+        }
+    }
+
+    /// Strips the stamp off a stamped chat message, for peers that don't support
+    /// `Capability::Timestamps`. Other messages are returned as they are.
+    ///
+    /// ```
+    /// use std::time::SystemTime;
+    /// use chat_rs::{Msg, Nick, Stamp};
+    ///
+    /// let stamp = Stamp { id: 1, time: SystemTime::now() };
+    /// let msg = Msg::StampedUserMsg(stamp, Nick::new("alice")?, "hi".into());
+    /// assert!(matches!(msg.without_stamp(), Msg::NickedUserMsg(_, s) if s == "hi"));
+    /// # Ok::<(), chat_rs::ChatError>(())
+    /// ```
+    pub fn without_stamp(self) -> Msg {
+        match self {
+            Msg::StampedUserMsg(_, nick, text) => Msg::NickedUserMsg(nick, text),
+            Msg::StampedHistoryMsg(stamp, nick, text) => Msg::HistoryMsg(nick, stamp.time, text),
+            msg => msg,
+        }
+    }
+
+    /// Turns a line typed by the user into the message to send: lines starting with a
+    /// `/` are commands, unless they start with `//`, which sends a chat message
+    /// starting with a single `/`. The commands that have a message of their own, like
+    /// `/msg <nick> <text>` and `/join <room>`, are sent as that message.
+    ///
+    /// ```
+    /// use chat_rs::Msg;
+    ///
+    /// assert!(matches!(Msg::from_input("hello"), Msg::UserMsg(s) if s == "hello"));
+    /// assert!(matches!(Msg::from_input("/me waves"), Msg::Command(s) if s == "me waves"));
+    /// assert!(matches!(Msg::from_input("//etc"), Msg::UserMsg(s) if s == "/etc"));
+    /// assert!(matches!(Msg::from_input("/msg bob hi"), Msg::PrivateMsg(n, s) if n.as_str() == "bob" && s == "hi"));
+    /// assert!(matches!(Msg::from_input("/join dev"), Msg::JoinRoom(r) if r.as_str() == "dev"));
+    /// ```
+    pub fn from_input(input: &str) -> Self {
+        match input.strip_prefix('/') {
+            Some(rest) if rest.starts_with('/') => Msg::UserMsg(rest.to_string()),
+            Some(command) => Self::parse_input_command(command)
+                .unwrap_or_else(|| Msg::Command(command.to_string())),
+            None => Msg::UserMsg(input.to_string()),
+        }
+    }
+
+    /// Parses the commands that have a message of their own, leaving anything else
+    /// (including bad arguments) for the server to run or complain about.
+    fn parse_input_command(command: &str) -> Option<Self> {
+        let command = command.trim();
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        let args = args.trim_start();
+        match name.to_ascii_lowercase().as_str() {
+            "msg" => {
+                let (to, text) = args.split_once(' ')?;
+                let text = text.trim_start();
+                if text.is_empty() {
+                    return None;
                 }
+                Some(Msg::PrivateMsg(Nick::new(to).ok()?, text.to_string()))
             }
+            "join" => Some(Msg::JoinRoom(Room::new(args).ok()?)),
+            "part" if args.is_empty() => Some(Msg::PartRoom),
+            "rooms" if args.is_empty() => Some(Msg::ListRooms),
+            // the rest of the line is the password, spaces included
+            "register" if !args.is_empty() => Some(Msg::Register(args.to_string())),
+            _ => None,
         }
     }
 
+    fn parse_nick(code: u8, nick: String) -> Result<Nick> {
+        Nick::new(nick).map_err(|_| ChatError::MalformedPayload(code))
+    }
+
+    fn parse_room(code: u8, room: String) -> Result<Room> {
+        Room::new(room).map_err(|_| ChatError::MalformedPayload(code))
+    }
+
     fn nicked_split(string: String) -> Option<(String, String)> {
-        let split_point = match string.find('\0') {
-            Some(n) => n,
-            None => return None,
-        };
+        let split_point = string.find('\0')?;
         let (nick, other) = string.split_at(split_point);
         Some((nick.into(), other[1..].into()))
     }
@@ -364,6 +1357,28 @@ impl Msg {
         output
     }
 
+    fn parse_hello(string: String) -> Option<Self> {
+        let (version, capabilities) = Self::nicked_split(string)?;
+        let version = version.parse().ok()?;
+        let capabilities = capabilities
+            .split(',')
+            .filter_map(Capability::from_name)
+            .collect();
+        Some(Msg::Hello(version, capabilities))
+    }
+
+    fn parse_roster(string: String) -> Option<Self> {
+        let (room, nicks) = Self::nicked_split(string)?;
+        let room = Room::new(room).ok()?;
+        let nicks = nicks
+            .split(',')
+            .filter(|nick| !nick.is_empty())
+            .map(Nick::new)
+            .collect::<Result<_>>()
+            .ok()?;
+        Some(Msg::Roster(room, nicks))
+    }
+
     /// Returns the underlying string of the message.
     /// This method also contains defaults for string-less messages,
     /// e.g. `Msg::ConnectionAccepted`.
@@ -372,16 +1387,54 @@ impl Msg {
         match self {
             UserMsg(s) => s.to_string(),
             NickedUserMsg(n, s) => Self::nicked_join(n, s),
+            HistoryMsg(n, t, s) => {
+                // times before the epoch can't be sent, and are clamped to it
+                let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                Self::nicked_join(n, &Self::nicked_join(&secs.to_string(), s))
+            }
+            StampedUserMsg(t, n, s) => Self::nicked_join(n, &Self::nicked_join(&t.encode(), s)),
+            StampedHistoryMsg(t, n, s) => Self::nicked_join(n, &Self::nicked_join(&t.encode(), s)),
 
             NickChange(s) => s.to_string(),
             NickedNickChange(n, s) => Self::nicked_join(n, s),
 
+            Authenticate(s) => s.to_string(),
+            Register(s) => s.to_string(),
+            Authenticated => String::new(),
+
             NickedConnect(n) => n.to_string(),
             NickedDisconnect(n) => n.to_string(),
 
             Command(s) => s.to_string(),
-            NickedCommand(n, s) => Self::nicked_join(n, s),
+            PrivateMsg(n, s) => Self::nicked_join(n, s),
+            NickedPrivateMsg(n, s) => Self::nicked_join(n, s),
+            NickedAction(n, s) => Self::nicked_join(n, s),
+
+            JoinRoom(r) => r.to_string(),
+            PartRoom => String::new(),
+            ListRooms => String::new(),
+            NickedJoin(n, r) => Self::nicked_join(n, r),
+            NickedPart(n, r) => Self::nicked_join(n, r),
+            Roster(r, nicks) => {
+                let nicks: Vec<&str> = nicks.iter().map(Nick::as_str).collect();
+                Self::nicked_join(r, &nicks.join(","))
+            }
 
+            Error(s) => s.to_string(),
+            ServerReply(s) => s.to_string(),
+            Notice(s) => s.to_string(),
+            ConnectionClosed(s) => s.to_string(),
+            ServerShutdown(s) => s.to_string(),
+            Kicked(s) => s.to_string(),
+
+            Ping => String::new(),
+            Pong => String::new(),
+
+            Hello(version, capabilities) => {
+                let names: Vec<&str> = capabilities.iter().map(Capability::name).collect();
+                Self::nicked_join(&version.to_string(), &names.join(","))
+            }
+            Rekey(s) => s.to_string(),
             ConnectionEncrypted => String::from("connection encrypted; commence ECDH"),
             ConnectionAccepted => String::from("connection accepted"),
             ConnectionRejected(s) => s.to_string(),
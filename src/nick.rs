@@ -0,0 +1,104 @@
+//! Validated nicknames.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use crate::{ChatError, Result};
+
+/// The maximum length of a nick, in characters.
+pub const MAX_NICK_LENGTH: usize = 24;
+
+/// A nickname that is safe to display and to embed in BCMP messages: between 1 and
+/// `MAX_NICK_LENGTH` characters, each of them an ASCII letter, digit, or one of `-_.[]`.
+///
+/// ```
+/// use chat_rs::Nick;
+///
+/// assert!(Nick::new("alice").is_ok());
+/// assert!(Nick::new("").is_err());
+/// assert!(Nick::new("bob\0evil").is_err());
+/// assert!(Nick::new("\x1b[2Jmallory").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Nick(String);
+
+impl Nick {
+    /// Validates `nick`, returning `ChatError::InvalidNick` if it breaks the rules.
+    pub fn new(nick: impl Into<String>) -> Result<Self> {
+        let nick = nick.into();
+        if nick.is_empty() {
+            return Err(ChatError::InvalidNick("nick must not be empty"));
+        }
+        if nick.chars().count() > MAX_NICK_LENGTH {
+            return Err(ChatError::InvalidNick("nick is too long"));
+        }
+        if !nick.chars().all(Self::is_valid_char) {
+            return Err(ChatError::InvalidNick(
+                "nick may only contain letters, digits and -_.[]",
+            ));
+        }
+        Ok(Nick(nick))
+    }
+
+    fn is_valid_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || "-_.[]".contains(c)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for Nick {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Nick {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Nick {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Nick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Nick {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for Nick {
+    type Error = ChatError;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl From<Nick> for String {
+    fn from(nick: Nick) -> Self {
+        nick.0
+    }
+}
@@ -0,0 +1,116 @@
+//! Validated room names.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use crate::{ChatError, Result, MAX_NICK_LENGTH};
+
+/// The room every user is in after connecting.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// The name of a chat room: between 1 and `MAX_NICK_LENGTH` characters, each of them
+/// a lowercase ASCII letter, a digit, or one of `-_.`. Uppercase letters are
+/// lowercased, so that `Dev` and `dev` are the same room.
+///
+/// ```
+/// use chat_rs::Room;
+///
+/// assert_eq!(Room::new("Dev").unwrap().as_str(), "dev");
+/// assert!(Room::new("").is_err());
+/// assert!(Room::new("two words").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Room(String);
+
+impl Room {
+    /// Validates `room`, returning `ChatError::InvalidRoom` if it breaks the rules.
+    pub fn new(room: impl Into<String>) -> Result<Self> {
+        let mut room = room.into();
+        room.make_ascii_lowercase();
+        if room.is_empty() {
+            return Err(ChatError::InvalidRoom("room name must not be empty"));
+        }
+        if room.chars().count() > MAX_NICK_LENGTH {
+            return Err(ChatError::InvalidRoom("room name is too long"));
+        }
+        if !room.chars().all(Self::is_valid_char) {
+            return Err(ChatError::InvalidRoom(
+                "room name may only contain letters, digits and -_.",
+            ));
+        }
+        Ok(Room(room))
+    }
+
+    /// Returns the room every user is in after connecting, `DEFAULT_ROOM`.
+    pub fn lobby() -> Self {
+        Room(DEFAULT_ROOM.to_string())
+    }
+
+    fn is_valid_char(c: char) -> bool {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self::lobby()
+    }
+}
+
+impl Deref for Room {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Room {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Room {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Room {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Room {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for Room {
+    type Error = ChatError;
+
+    fn try_from(s: String) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl From<Room> for String {
+    fn from(room: Room) -> Self {
+        room.0
+    }
+}
@@ -0,0 +1,72 @@
+//! Keeping track of who is in the client's room.
+
+use std::collections::BTreeSet;
+
+use crate::{Msg, Nick, Room};
+
+/// The users in the room a client is in, as far as the server has told it. It starts
+/// out from the `Msg::Roster` the server sends on connecting and on joining a room, and
+/// is kept up to date by the connects, disconnects, joins, parts and nick changes that
+/// follow.
+///
+/// ```
+/// use chat_rs::{Msg, Nick, Room, Roster};
+///
+/// let alice = Nick::new("alice").unwrap();
+/// let bob = Nick::new("bob").unwrap();
+/// let carol = Nick::new("carol").unwrap();
+///
+/// let mut roster = Roster::default();
+/// roster.update(&Msg::Roster(Room::lobby(), vec![alice.clone()]));
+/// roster.update(&Msg::NickedConnect(carol.clone()));
+/// roster.update(&Msg::NickedNickChange(alice, bob.clone()));
+/// assert_eq!(roster.nicks().collect::<Vec<_>>(), [&bob, &carol]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Roster {
+    room: Room,
+    nicks: BTreeSet<Nick>,
+}
+
+impl Roster {
+    /// Applies a message from the server. Returns whether the roster changed.
+    pub fn update(&mut self, msg: &Msg) -> bool {
+        match msg {
+            Msg::Roster(room, nicks) => {
+                self.room = room.clone();
+                self.nicks = nicks.iter().cloned().collect();
+                true
+            }
+            // joins and parts are only sent to the room they happen in
+            Msg::NickedConnect(nick) | Msg::NickedJoin(nick, _) => self.nicks.insert(nick.clone()),
+            Msg::NickedDisconnect(nick) | Msg::NickedPart(nick, _) => self.nicks.remove(nick),
+            // nick changes are sent to everyone
+            Msg::NickedNickChange(old, new) => {
+                self.nicks.remove(old) && self.nicks.insert(new.clone())
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets everyone, e.g. after the connection is lost.
+    pub fn clear(&mut self) {
+        self.nicks.clear();
+    }
+
+    pub fn room(&self) -> &Room {
+        &self.room
+    }
+
+    /// Returns the nicks in the room, sorted.
+    pub fn nicks(&self) -> impl Iterator<Item = &Nick> {
+        self.nicks.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nicks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nicks.is_empty()
+    }
+}